@@ -0,0 +1,36 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::path::PathBuf;
+
+const EMBED_CA_BUNDLE: &str = "PTEX_EMBED_CA_BUNDLE";
+
+fn main() -> Result<(), String> {
+    let out_dir = PathBuf::from(
+        std::env::var("OUT_DIR")
+            .map_err(|e| format!("Expected OUT_DIR to be set for build script: {e}"))?,
+    );
+    let dst = out_dir.join("ca-bundle.pem");
+
+    println!("cargo:rerun-if-env-changed={EMBED_CA_BUNDLE}");
+    match std::env::var_os(EMBED_CA_BUNDLE).filter(|value| !value.is_empty()) {
+        Some(src) => {
+            let src = PathBuf::from(src);
+            println!("cargo:rerun-if-changed={src}", src = src.display());
+            std::fs::copy(&src, &dst).map_err(|e| {
+                format!(
+                    "Failed to copy the CA bundle to embed from {src} to {dst}: {e}",
+                    src = src.display(),
+                    dst = dst.display()
+                )
+            })?;
+        }
+        None => std::fs::write(&dst, b"").map_err(|e| {
+            format!(
+                "Failed to write an empty CA bundle to {dst}: {e}",
+                dst = dst.display()
+            )
+        })?,
+    }
+    Ok(())
+}
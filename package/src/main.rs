@@ -101,6 +101,12 @@ impl Display for SpecifiedPath {
 struct Args {
     #[arg(long, help = "Override the default --target for this platform.")]
     target: Option<String>,
+    #[arg(
+        long,
+        help = "A PEM CA certificate bundle to embed in the ptex binary for use when no other CA \
+            certificates are configured at runtime."
+    )]
+    ca_bundle: Option<PathBuf>,
     #[arg(
         help = "The destination directory for the ptex binary and checksum file.",
         default_value_t = SpecifiedPath::new("dist")
@@ -134,8 +140,19 @@ fn main() -> ExitResult {
     let workspace_root = PathBuf::from(cargo_manifest_dir).join("..");
     let output_root = PathBuf::from(out_dir).join("dist");
     let output_bin_dir = output_root.join("bin");
+    let mut command = Command::new(cargo);
+    if let Some(ca_bundle) = args.ca_bundle {
+        // N.B.: The ptex build script embeds the CA bundle pointed to by this env var.
+        let ca_bundle = ca_bundle.canonicalize().map_err(|e| {
+            Code::FAILURE.with_message(format!(
+                "Failed to resolve the CA bundle {ca_bundle}: {e}",
+                ca_bundle = ca_bundle.display()
+            ))
+        })?;
+        command.env("PTEX_EMBED_CA_BUNDLE", ca_bundle);
+    }
     execute(
-        Command::new(cargo)
+        command
             .args([
                 "install",
                 "--path",
@@ -0,0 +1,381 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+/// A content-addressed store of fetched files keyed by their sha256 hash.
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+pub(crate) struct Entry {
+    pub(crate) hash: String,
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) last_used: SystemTime,
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|char| char.is_ascii_hexdigit())
+}
+
+impl Cache {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn entries_dir(&self) -> PathBuf {
+        self.dir.join("sha256")
+    }
+
+    fn entry_path(&self, hash: &str) -> Result<PathBuf> {
+        if !is_sha256(hash) {
+            return Err(anyhow!(
+                "The cache key {hash:?} is not a sha256 hex digest."
+            ));
+        }
+        Ok(self.entries_dir().join(hash.to_ascii_lowercase()))
+    }
+
+    /// Opens the cached file with the given hash if present, of the expected size and with
+    /// content matching its hash. Corrupt entries are evicted.
+    ///
+    /// A hit refreshes the entry's last used time for the purposes of pruning.
+    pub(crate) fn get(&self, hash: &str, size: Option<u64>) -> Result<Option<File>> {
+        let path = self.entry_path(hash)?;
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed to open cache entry {path}", path = path.display())
+                });
+            }
+        };
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of {path}", path = path.display()))?;
+        let evict = |file: File| {
+            drop(file);
+            std::fs::remove_file(&path).with_context(|| {
+                format!(
+                    "Failed to remove corrupt cache entry {path}",
+                    path = path.display()
+                )
+            })
+        };
+        if let Some(size) = size
+            && size != metadata.len()
+        {
+            evict(file)?;
+            return Ok(None);
+        }
+        // N.B.: We hash the entry before handing it out instead of as it is copied so that corrupt
+        // content is never written anywhere.
+        let digest = digest(&mut file)
+            .with_context(|| format!("Failed to hash {path}", path = path.display()))?;
+        if !digest.eq_ignore_ascii_case(hash) {
+            evict(file)?;
+            return Ok(None);
+        }
+        file.rewind()
+            .with_context(|| format!("Failed to read cache entry {path}", path = path.display()))?;
+        // N.B.: Failing to record the last use only affects pruning order; so we ignore errors.
+        let _ = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        Ok(Some(file))
+    }
+
+    /// Creates a writer that atomically adds an entry with the given hash to the cache once
+    /// committed.
+    pub(crate) fn writer(&self, hash: &str) -> Result<Writer> {
+        let path = self.entry_path(hash)?;
+        let entries_dir = self.entries_dir();
+        std::fs::create_dir_all(&entries_dir).with_context(|| {
+            format!(
+                "Failed to create cache directory {dir}",
+                dir = entries_dir.display()
+            )
+        })?;
+        let file = tempfile::Builder::new()
+            .prefix(".tmp.")
+            .tempfile_in(&entries_dir)
+            .with_context(|| {
+                format!(
+                    "Failed to create a temporary file in {dir}",
+                    dir = entries_dir.display()
+                )
+            })?;
+        Ok(Writer {
+            path,
+            file,
+            error: None,
+        })
+    }
+
+    pub(crate) fn entries(&self) -> Result<Vec<Entry>> {
+        let entries_dir = self.entries_dir();
+        let read_dir = match std::fs::read_dir(&entries_dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "Failed to list cache directory {dir}",
+                        dir = entries_dir.display()
+                    )
+                });
+            }
+        };
+        let mut entries = vec![];
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.with_context(|| {
+                format!(
+                    "Failed to list cache directory {dir}",
+                    dir = entries_dir.display()
+                )
+            })?;
+            let Some(hash) = dir_entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !is_sha256(&hash) {
+                continue;
+            }
+            let path = dir_entry.path();
+            let metadata = dir_entry.metadata().with_context(|| {
+                format!("Failed to read metadata of {path}", path = path.display())
+            })?;
+            entries.push(Entry {
+                hash,
+                path,
+                size: metadata.len(),
+                last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+        entries.sort_by_key(|entry| entry.last_used);
+        Ok(entries)
+    }
+
+    /// Removes entries whose content no longer matches their hash, returning the removed entries.
+    pub(crate) fn verify(&self) -> Result<Vec<Entry>> {
+        let mut corrupt = vec![];
+        for entry in self.entries()? {
+            let hash = digest_path(&entry.path)
+                .with_context(|| format!("Failed to hash {path}", path = entry.path.display()))?;
+            if hash != entry.hash.to_ascii_lowercase() {
+                remove(&entry.path)?;
+                corrupt.push(entry);
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// Removes entries last used longer ago than `max_age` and then the least recently used
+    /// entries until the total size of the cache is no more than `max_size`, returning the
+    /// removed entries.
+    pub(crate) fn prune(
+        &self,
+        max_age: Option<Duration>,
+        max_size: Option<u64>,
+    ) -> Result<Vec<Entry>> {
+        let now = SystemTime::now();
+        let mut total_size: u64 = 0;
+        let mut remaining = vec![];
+        let mut pruned = vec![];
+        for entry in self.entries()? {
+            let age = now.duration_since(entry.last_used).unwrap_or_default();
+            if max_age.is_some_and(|max_age| age > max_age) {
+                pruned.push(entry);
+            } else {
+                total_size += entry.size;
+                remaining.push(entry);
+            }
+        }
+        if let Some(max_size) = max_size {
+            // N.B.: Entries are sorted least recently used first.
+            for entry in remaining {
+                if total_size <= max_size {
+                    break;
+                }
+                total_size -= entry.size;
+                pruned.push(entry);
+            }
+        }
+        for entry in &pruned {
+            remove(&entry.path)?;
+        }
+        Ok(pruned)
+    }
+}
+
+pub(crate) fn digest_path(path: &Path) -> io::Result<String> {
+    digest(File::open(path)?)
+}
+
+fn digest(reader: impl Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let amount_read = {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(hex::encode(hasher.finalize()));
+            }
+            hasher.update(buf);
+            buf.len()
+        };
+        reader.consume(amount_read);
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    std::fs::remove_file(path)
+        .with_context(|| format!("Failed to remove cache entry {path}", path = path.display()))
+}
+
+/// Writes a new cache entry to a temporary file that is only moved into place when committed.
+///
+/// Write failures are recorded rather than returned so that a problem with the cache never fails
+/// the fetch it is attached to.
+pub(crate) struct Writer {
+    path: PathBuf,
+    file: NamedTempFile,
+    error: Option<io::Error>,
+}
+
+impl Writer {
+    pub(crate) fn commit(self) -> Result<()> {
+        if let Some(err) = self.error {
+            return Err(err).context("Failed to write cache entry");
+        }
+        self.file.persist(&self.path).with_context(|| {
+            format!(
+                "Failed to move cache entry into place at {path}",
+                path = self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.error.is_none()
+            && let Err(err) = self.file.write_all(buf)
+        {
+            self.error = Some(err);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.error.is_none()
+            && let Err(err) = self.file.flush()
+        {
+            self.error = Some(err);
+        }
+        Ok(())
+    }
+}
+
+/// Copies everything written to it to both of its outputs.
+pub(crate) struct Tee<A: Write, B: Write>(pub(crate) A, pub(crate) B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::time::{Duration, SystemTime};
+
+    use sha2::{Digest, Sha256};
+
+    use super::Cache;
+
+    fn add(cache: &Cache, content: &[u8], age: Duration) -> String {
+        let hash = hex::encode(Sha256::digest(content));
+        let mut writer = cache.writer(&hash).unwrap();
+        writer.write_all(content).unwrap();
+        writer.commit().unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(cache.entry_path(&hash).unwrap())
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+        hash
+    }
+
+    #[test]
+    fn get() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tempdir.path().to_path_buf());
+        let hash = add(&cache, b"content", Duration::ZERO);
+
+        let mut content = String::new();
+        cache
+            .get(&hash, Some(7))
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!("content", content);
+
+        assert!(cache.get(&"0".repeat(64), None).unwrap().is_none());
+        assert!(cache.get("../escape", None).is_err());
+
+        // A size mismatch is treated as a corrupt entry and evicted.
+        assert!(cache.get(&hash, Some(8)).unwrap().is_none());
+        assert!(cache.get(&hash, None).unwrap().is_none());
+
+        // As is content that no longer matches its hash, even at the expected size.
+        let hash = add(&cache, b"content", Duration::ZERO);
+        std::fs::write(cache.entry_path(&hash).unwrap(), b"CONTENT").unwrap();
+        assert!(cache.get(&hash, Some(7)).unwrap().is_none());
+        assert!(!cache.entry_path(&hash).unwrap().exists());
+    }
+
+    #[test]
+    fn prune() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(tempdir.path().to_path_buf());
+        let day = Duration::from_secs(24 * 60 * 60);
+        let oldest = add(&cache, b"oldest", 3 * day);
+        let older = add(&cache, b"older", 2 * day);
+        let old = add(&cache, b"old", day);
+        let new = add(&cache, b"new", Duration::ZERO);
+
+        let hashes = |entries: Vec<super::Entry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.hash)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![oldest],
+            hashes(cache.prune(Some(5 * day / 2), None).unwrap())
+        );
+        assert_eq!(vec![older], hashes(cache.prune(None, Some(6)).unwrap()));
+        assert_eq!(vec![old, new], hashes(cache.entries().unwrap()));
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use serde::Deserialize;
+
+use crate::error::{Category, Failure};
+use crate::fetch::{Netrc, Options, Proxy};
+use crate::placeholders;
+use crate::redact::redact_url;
+
+#[derive(Deserialize)]
+struct LiftFile {
+    name: String,
+    key: Option<String>,
+    size: Option<u64>,
+    hash: Option<String>,
+    source: Option<String>,
+}
+
+impl LiftFile {
+    /// Whether this is a ptex binary, going by the names the ptex releases are published under;
+    /// e.g.: ptex-linux-x86_64.
+    fn is_ptex(&self) -> bool {
+        let is_ptex_name = |name: &str| {
+            let name = Path::new(name)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(name);
+            name == "ptex" || name.starts_with("ptex-") || name.starts_with("ptex.")
+        };
+        is_ptex_name(&self.name) || self.key.as_deref().is_some_and(is_ptex_name)
+    }
+}
+
+#[derive(Deserialize)]
+struct Binding {
+    exe: String,
+}
+
+#[derive(Default, Deserialize)]
+struct Boot {
+    #[serde(default)]
+    bindings: BTreeMap<String, Binding>,
+}
+
+#[derive(Default, Deserialize)]
+struct Lift {
+    #[serde(default)]
+    files: Vec<LiftFile>,
+    #[serde(default)]
+    boot: Boot,
+}
+
+impl Lift {
+    /// Whether the named boot binding runs a ptex binary that is one of the lift files; e.g.: via
+    /// an exe of `{ptex}` or `{scie.files.ptex-linux-x86_64}`.
+    fn runs_ptex(&self, binding: &str) -> bool {
+        let Some(binding) = self.boot.bindings.get(binding) else {
+            return false;
+        };
+        let Some(file) = binding
+            .exe
+            .trim()
+            .strip_prefix('{')
+            .and_then(|exe| exe.strip_suffix('}'))
+        else {
+            return false;
+        };
+        let file = file.strip_prefix("scie.files.").unwrap_or(file);
+        self.files
+            .iter()
+            .find(|lift_file| lift_file.name == file || lift_file.key.as_deref() == Some(file))
+            .is_some_and(LiftFile::is_ptex)
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct Scie {
+    #[serde(default)]
+    lift: Lift,
+}
+
+/// The URL(s) a file can be fetched from: either a single URL or a list of mirrors to try in
+/// order.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Urls {
+    Single(String),
+    Mirrors(Vec<String>),
+}
+
+impl Urls {
+    pub(crate) fn as_slice(&self) -> &[String] {
+        match self {
+            Urls::Single(url) => std::slice::from_ref(url),
+            Urls::Mirrors(urls) => urls.as_slice(),
+        }
+    }
+}
+
+/// Options for fetching a particular file that supplement or override those passed on the
+/// command line.
+#[derive(Deserialize)]
+pub(crate) struct Request {
+    url: Urls,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    netrc: Option<Netrc>,
+    timeout: Option<f64>,
+    proxy: Option<Proxy>,
+    compressed: Option<bool>,
+}
+
+/// A file's entry in the top-level "ptex" object: either just its URL(s) or its URL(s) along with
+/// options for fetching it.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Entry {
+    Urls(Urls),
+    Request(Request),
+}
+
+impl Entry {
+    pub(crate) fn urls(&self) -> Result<Vec<String>> {
+        let urls = match self {
+            Entry::Urls(urls) => urls.as_slice(),
+            Entry::Request(request) => request.url.as_slice(),
+        };
+        urls.iter()
+            .map(|url| {
+                placeholders::expand(url)
+                    .with_context(|| format!("Failed to expand URL {url}", url = redact_url(url)))
+            })
+            .collect()
+    }
+
+    pub(crate) fn options(&self, defaults: &Options) -> Result<Options> {
+        let mut options = defaults.clone();
+        let Entry::Request(request) = self else {
+            return Ok(options);
+        };
+        if !request.headers.is_empty() {
+            // N.B.: Headers configured for the file take precedence over same-named headers passed
+            // on the command line.
+            options.headers.retain(|header| {
+                let name = header
+                    .split_once(':')
+                    .map_or(header.as_str(), |(name, _)| name);
+                !request
+                    .headers
+                    .keys()
+                    .any(|key| key.eq_ignore_ascii_case(name.trim()))
+            });
+            for (name, value) in &request.headers {
+                let value = placeholders::expand(value)
+                    .with_context(|| format!("Failed to expand the value of header {name}"))?;
+                options.headers.push(format!("{name}: {value}"));
+            }
+        }
+        if let Some(netrc) = request.netrc {
+            options.netrc = netrc;
+        }
+        if let Some(timeout) = request.timeout {
+            options.timeouts.max_time = Some(
+                Duration::try_from_secs_f64(timeout)
+                    .map_err(|e| anyhow!("Invalid timeout of {timeout}: {e}"))?,
+            );
+        }
+        if let Some(compressed) = request.compressed {
+            options.compressed = compressed;
+        }
+        if let Some(proxy) = request.proxy.as_ref() {
+            let expand = |value: &Option<String>, field: &str| {
+                value
+                    .as_deref()
+                    .map(|value| {
+                        placeholders::expand(value)
+                            .with_context(|| format!("Failed to expand the proxy {field}"))
+                    })
+                    .transpose()
+            };
+            if let Some(url) = expand(&proxy.url, "url")? {
+                options.proxy.url = Some(url);
+            }
+            if let Some(user) = expand(&proxy.user, "user")? {
+                options.proxy.user = Some(user);
+            }
+            if let Some(noproxy) = expand(&proxy.noproxy, "noproxy")? {
+                options.proxy.noproxy = Some(noproxy);
+            }
+            if let Some(cacert) = proxy.cacert.as_ref() {
+                options.proxy.cacert = Some(cacert.clone());
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Config {
+    #[serde(default)]
+    scie: Scie,
+    pub(crate) ptex: BTreeMap<PathBuf, Entry>,
+}
+
+impl Config {
+    pub(crate) fn parse<R: Read>(reader: R) -> Result<Self> {
+        let config: Self = serde_json::from_reader(reader).context(Failure::new(
+            Category::Config,
+            "Failed to parse ptex config",
+        ))?;
+        Ok(config)
+    }
+
+    /// The names of the lift manifest files that are provided by a source binding that runs ptex
+    /// instead of being embedded in the scie.
+    pub(crate) fn sourced_files(&self) -> impl Iterator<Item = &Path> {
+        let lift = &self.scie.lift;
+        lift.files
+            .iter()
+            .filter(|file| {
+                file.source
+                    .as_deref()
+                    .is_some_and(|binding| lift.runs_ptex(binding))
+            })
+            .map(|file| Path::new(&file.name))
+    }
+
+    pub(crate) fn fingerprint(&self, file_path: &Path) -> Option<Fingerprint> {
+        self.scie
+            .lift
+            .files
+            .iter()
+            .find(|file| Path::new(&file.name) == file_path)
+            .map(|file| Fingerprint {
+                size: file.size,
+                hash: file.hash.clone(),
+            })
+    }
+}
+
+/// The expected size and sha256 hash of a fetched file, as recorded in the lift manifest.
+#[derive(Clone, Default)]
+pub(crate) struct Fingerprint {
+    pub(crate) size: Option<u64>,
+    pub(crate) hash: Option<String>,
+}
@@ -0,0 +1,170 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fmt::{Display, Formatter};
+
+// N.B.: The curl crate does not expose predicates for these.
+const CURLE_SSL_PINNEDPUBKEYNOTMATCH: u32 = 90;
+pub(crate) const CURLE_PROXY: u32 = 97;
+
+/// A class of failure, each with its own stable process exit code.
+///
+/// Where curl has an exit code for the same class of failure, we use it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Category {
+    Other,
+    Usage,
+    Url,
+    ProxyResolve,
+    HostResolve,
+    Connect,
+    HttpStatus,
+    Write,
+    Timeout,
+    TlsHandshake,
+    Network,
+    TlsVerify,
+    Proxy,
+    Config,
+    NoMapping,
+    Integrity,
+    TransientHttpStatus,
+    Archive,
+    Interrupted,
+}
+
+impl Category {
+    pub(crate) fn exit_code(self) -> i32 {
+        match self {
+            Category::Other => 1,
+            Category::Usage => 2,
+            Category::Url => 3,
+            Category::ProxyResolve => 5,
+            Category::HostResolve => 6,
+            Category::Connect => 7,
+            Category::HttpStatus => 22,
+            Category::Write => 23,
+            Category::Timeout => 28,
+            Category::TlsHandshake => 35,
+            Category::Network => 56,
+            Category::TlsVerify => 60,
+            Category::Proxy => 97,
+            Category::Config => 100,
+            Category::NoMapping => 101,
+            Category::Integrity => 102,
+            Category::TransientHttpStatus => 103,
+            Category::Archive => 104,
+            // N.B.: The exit code conventionally used by shells for a process terminated by
+            // SIGINT.
+            Category::Interrupted => 130,
+        }
+    }
+
+    fn from_curl(err: &curl::Error) -> Self {
+        if err.is_url_malformed() || err.is_unsupported_protocol() {
+            Category::Url
+        } else if err.is_couldnt_resolve_proxy() {
+            Category::ProxyResolve
+        } else if err.is_couldnt_resolve_host() {
+            Category::HostResolve
+        } else if err.is_couldnt_connect() {
+            Category::Connect
+        } else if err.is_http_returned_error() {
+            Category::HttpStatus
+        } else if err.is_write_error() {
+            Category::Write
+        } else if err.is_operation_timedout() {
+            Category::Timeout
+        } else if err.is_peer_failed_verification()
+            || err.is_ssl_cacert()
+            || err.is_ssl_cacert_badfile()
+            || err.is_ssl_issuer_error()
+            || err.code() == CURLE_SSL_PINNEDPUBKEYNOTMATCH
+        {
+            Category::TlsVerify
+        } else if err.is_ssl_connect_error()
+            || err.is_ssl_certproblem()
+            || err.is_ssl_cipher()
+            || err.is_ssl_engine_notfound()
+            || err.is_ssl_engine_setfailed()
+            || err.is_ssl_engine_initfailed()
+        {
+            Category::TlsHandshake
+        } else if err.is_partial_file()
+            || err.is_recv_error()
+            || err.is_send_error()
+            || err.is_got_nothing()
+            || err.is_http2_error()
+            || err.is_http2_stream_error()
+        {
+            Category::Network
+        } else if err.code() == CURLE_PROXY {
+            Category::Proxy
+        } else {
+            Category::Other
+        }
+    }
+
+    /// Determines the category of an error from the outermost `Failure` in its chain, falling back
+    /// to classifying the curl error at its root, if any.
+    pub(crate) fn of(err: &anyhow::Error) -> Self {
+        if let Some(failure) = err.downcast_ref::<Failure>() {
+            failure.category
+        } else if let Some(err) = err.downcast_ref::<curl::Error>() {
+            Category::from_curl(err)
+        } else {
+            Category::Other
+        }
+    }
+}
+
+/// An error message tagged with the category of failure it describes.
+///
+/// These are typically attached as `anyhow` context so that the category survives further
+/// context being added on the way up to `main`.
+#[derive(Debug)]
+pub(crate) struct Failure {
+    category: Category,
+    message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(category: Category, message: impl Into<String>) -> Self {
+        Self {
+            category,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, anyhow};
+
+    use super::{Category, Failure};
+
+    #[test]
+    fn category_of() {
+        let err = anyhow!("Uncategorized").context("Outer");
+        assert_eq!(Category::Other, Category::of(&err));
+
+        let err = anyhow::Error::new(Failure::new(Category::Integrity, "Hash mismatch"))
+            .context("Failed to source file");
+        assert_eq!(Category::Integrity, Category::of(&err));
+        assert_eq!(102, Category::of(&err).exit_code());
+
+        let err = Err::<(), _>(anyhow!("Root"))
+            .context(Failure::new(Category::NoMapping, "No mapping"))
+            .context(Failure::new(Category::Config, "Invalid config"))
+            .unwrap_err();
+        assert_eq!(Category::Config, Category::of(&err));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// A fetch progress event, serialized as a JSON object with an "event" key naming its kind.
+///
+/// Every event carries the URL originally requested; so a consumer can tell concurrent fetches
+/// apart.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    Start {
+        url: &'a str,
+        /// The expected size of the file, if known from the lift manifest.
+        size: Option<u64>,
+    },
+    Progress {
+        url: &'a str,
+        bytes: u64,
+        total: Option<u64>,
+        /// The average rate of the current attempt in bytes per second.
+        rate: u64,
+    },
+    Redirect {
+        url: &'a str,
+        status: u32,
+        from: &'a str,
+        to: &'a str,
+    },
+    Retry {
+        url: &'a str,
+        error: &'a str,
+        wait_seconds: f64,
+        retries_left: u32,
+        resume_from: u64,
+    },
+    Complete {
+        url: &'a str,
+        effective_url: &'a str,
+        bytes: u64,
+        not_modified: bool,
+    },
+    Error {
+        url: &'a str,
+        error: &'a str,
+        exit_code: i32,
+    },
+}
+
+/// A destination for newline-delimited JSON progress events, for programs that embed ptex and
+/// render their own progress.
+#[derive(Clone)]
+pub(crate) struct Events(Arc<Mutex<Box<dyn Write + Send>>>);
+
+impl Events {
+    pub(crate) fn new(output: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(output))))
+    }
+
+    pub(crate) fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+
+    /// Emits events to the given file descriptor, which the parent process must have left open.
+    #[cfg(unix)]
+    pub(crate) fn to_fd(fd: u32) -> Result<Self> {
+        // N.B.: Opening the descriptor by path lets us share it without taking ownership of it.
+        let file = File::options()
+            .append(true)
+            .open(format!("/dev/fd/{fd}"))
+            .with_context(|| format!("Failed to open file descriptor {fd} for progress events"))?;
+        Ok(Self::new(file))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn to_fd(fd: u32) -> Result<Self> {
+        anyhow::bail!("Emitting progress events to file descriptor {fd} is only supported on Unix.")
+    }
+
+    pub(crate) fn emit(&self, event: &Event) {
+        // N.B.: A consumer that has gone away should not fail the fetch; so write errors are
+        // ignored.
+        if let Ok(mut output) = self.0.lock()
+            && let Ok(mut line) = serde_json::to_vec(event)
+        {
+            line.push(b'\n');
+            let _ = output.write_all(&line).and_then(|()| output.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::Events;
+
+    /// A buffer of emitted events that can be inspected after being handed off to a fetch.
+    #[derive(Clone, Default)]
+    pub(crate) struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl Recorder {
+        pub(crate) fn events(&self) -> Events {
+            Events::new(self.clone())
+        }
+
+        pub(crate) fn recorded(&self) -> Vec<serde_json::Value> {
+            self.0
+                .lock()
+                .unwrap()
+                .split(|byte| *byte == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect()
+        }
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,827 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use tar::EntryType;
+
+use crate::error::{Category, Failure};
+use crate::fetch::{Options, Response, fetch};
+use crate::interrupt::Pending;
+use crate::output::parent_dir;
+use crate::redact::redact_url;
+
+/// The archive types we can extract, named as in the `type` field of scie lift manifest files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum ArchiveType {
+    Zip,
+    Tar,
+    TarBz2,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+const ARCHIVE_TYPES: &[(&str, ArchiveType)] = &[
+    ("zip", ArchiveType::Zip),
+    ("tar", ArchiveType::Tar),
+    ("tar.bz2", ArchiveType::TarBz2),
+    ("tbz2", ArchiveType::TarBz2),
+    ("tar.gz", ArchiveType::TarGz),
+    ("tgz", ArchiveType::TarGz),
+    ("tar.xz", ArchiveType::TarXz),
+    ("tar.lzma", ArchiveType::TarXz),
+    ("tlz", ArchiveType::TarXz),
+    ("tar.zst", ArchiveType::TarZst),
+    ("tzst", ArchiveType::TarZst),
+];
+
+impl ArchiveType {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        ARCHIVE_TYPES
+            .iter()
+            .find(|(name, _)| *name == value)
+            .map(|(_, archive_type)| *archive_type)
+    }
+
+    /// Infers the archive type from the extension of a file name.
+    pub(crate) fn from_file_name(file_name: &str) -> Option<Self> {
+        let file_name = file_name.to_ascii_lowercase();
+        ARCHIVE_TYPES
+            .iter()
+            .find(|(name, _)| file_name.ends_with(&format!(".{name}")))
+            .map(|(_, archive_type)| *archive_type)
+    }
+
+    fn name(self) -> &'static str {
+        ARCHIVE_TYPES
+            .iter()
+            .find(|(_, archive_type)| *archive_type == self)
+            .map(|(name, _)| *name)
+            .expect("Every archive type has a name.")
+    }
+
+    fn decompress<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            ArchiveType::Zip | ArchiveType::Tar => Box::new(reader),
+            ArchiveType::TarBz2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            ArchiveType::TarGz => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            // N.B.: The auto decoder handles both the xz and legacy lzma formats.
+            ArchiveType::TarXz => Box::new(xz2::read::XzDecoder::new_stream(
+                reader,
+                xz2::stream::Stream::new_auto_decoder(u64::MAX, 0)
+                    .context("Failed to initialize the xz decoder")?,
+            )),
+            ArchiveType::TarZst => Box::new(
+                zstd::stream::read::Decoder::new(reader)
+                    .context("Failed to initialize the zstd decoder")?,
+            ),
+        })
+    }
+}
+
+/// Which archive entries to extract and where to put them.
+#[derive(Clone, Default)]
+pub(crate) struct Selection {
+    /// The number of leading path components to strip from entry paths; entries with no
+    /// components left are skipped.
+    pub(crate) strip_components: usize,
+    /// Glob patterns selecting the entries to extract by their stripped paths. A pattern that
+    /// matches a directory selects everything under it. If empty, all entries are extracted.
+    pub(crate) include: Vec<String>,
+}
+
+impl Selection {
+    fn strip(&self, entry_path: &Path) -> Result<Option<PathBuf>> {
+        let components = normal_components(entry_path).ok_or_else(|| unsafe_entry(entry_path))?;
+        if components.len() <= self.strip_components {
+            return Ok(None);
+        }
+        Ok(Some(components[self.strip_components..].iter().collect()))
+    }
+
+    /// Returns the path to extract an entry to, relative to the extraction directory, or `None`
+    /// if the entry should be skipped.
+    fn select(&self, entry_path: &Path) -> Result<Option<PathBuf>> {
+        let Some(path) = self.strip(entry_path)? else {
+            return Ok(None);
+        };
+        if !self.include.is_empty() && !self.include.iter().any(|pattern| includes(pattern, &path))
+        {
+            return Ok(None);
+        }
+        Ok(Some(path))
+    }
+}
+
+/// Returns the components of a relative path or `None` if the path is absolute or has `..`
+/// components.
+fn normal_components(path: &Path) -> Option<Vec<&OsStr>> {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+fn includes(pattern: &str, path: &Path) -> bool {
+    let patterns = pattern
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .collect::<Vec<_>>();
+    let names = path.components().collect::<Vec<_>>();
+    !patterns.is_empty()
+        && patterns.len() <= names.len()
+        && patterns.iter().zip(names).all(|(pattern, name)| {
+            glob_match(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name
+                    .as_os_str()
+                    .to_string_lossy()
+                    .chars()
+                    .collect::<Vec<_>>(),
+            )
+        })
+}
+
+/// Matches a single path component against a pattern where `*` matches any run of characters
+/// and `?` matches any one character.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', rest)), _) => {
+            glob_match(rest, name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((expected, rest)), Some((actual, name_rest))) => {
+            (*expected == '?' || expected == actual) && glob_match(rest, name_rest)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+fn unsafe_entry(entry_path: &Path) -> anyhow::Error {
+    anyhow::Error::new(Failure::new(
+        Category::Archive,
+        format!(
+            "Refusing to extract the archive entry {path} since it would land outside of the \
+            extraction directory",
+            path = entry_path.display()
+        ),
+    ))
+}
+
+/// Creates the parent directories of `dst`, checking they resolve inside `root`, and removes any
+/// existing non-directory at `dst`; so that an entry can never be written through a symlink.
+///
+/// Returns the resolved parent directory.
+fn prepare(root: &Path, entry_path: &Path, dst: &Path) -> Result<PathBuf> {
+    let parent = parent_dir(dst);
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("Failed to create the directory {}", parent.display()))?;
+    let parent = parent
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve the directory {}", parent.display()))?;
+    if !parent.starts_with(root) {
+        return Err(unsafe_entry(entry_path));
+    }
+    if let Ok(metadata) = dst.symlink_metadata()
+        && !metadata.is_dir()
+    {
+        std::fs::remove_file(dst)
+            .with_context(|| format!("Failed to replace the file {}", dst.display()))?;
+    }
+    Ok(parent)
+}
+
+/// Checks that a symlink in the resolved directory `parent` pointing to `target` resolves inside
+/// `root`, following any symlinks already extracted along the way.
+///
+/// Since entries extracted later could change where a path that does not exist yet resolves, a
+/// `..` is only allowed while every component before it exists.
+fn check_symlink(root: &Path, entry_path: &Path, parent: PathBuf, target: &Path) -> Result<()> {
+    let mut resolved = parent;
+    let mut exists = true;
+    for component in target.components() {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+                if exists {
+                    match resolved.canonicalize() {
+                        Ok(path) => resolved = path,
+                        Err(_) => exists = false,
+                    }
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir if exists => {
+                resolved.pop();
+            }
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(unsafe_entry(entry_path));
+            }
+        }
+        if !resolved.starts_with(root) {
+            return Err(unsafe_entry(entry_path));
+        }
+    }
+    Ok(())
+}
+
+/// A symlink extracted from an archive.
+struct Symlink {
+    entry_path: PathBuf,
+    dst: PathBuf,
+    target: PathBuf,
+}
+
+/// Checks the extracted symlinks again once all entries are in place.
+///
+/// A symlink is checked as it is extracted against the entries extracted before it, but a later
+/// entry can replace a path it resolves through; e.g.: a file it steps out of with `..` can be
+/// replaced by a symlink elsewhere.
+fn recheck_symlinks(root: &Path, symlinks: &[Symlink]) -> Result<()> {
+    for symlink in symlinks {
+        let parent = parent_dir(&symlink.dst);
+        let parent = parent
+            .canonicalize()
+            .with_context(|| format!("Failed to resolve the directory {}", parent.display()))?;
+        check_symlink(root, &symlink.entry_path, parent, &symlink.target)?;
+    }
+    Ok(())
+}
+
+/// Creates the directory `dst`, checking it resolves inside `root`.
+fn create_dir(root: &Path, entry_path: &Path, dst: &Path) -> Result<()> {
+    prepare(root, entry_path, dst)?;
+    std::fs::create_dir_all(dst)
+        .with_context(|| format!("Failed to create the directory {}", dst.display()))
+}
+
+fn unpack_tar(reader: impl Read, root: &Path, selection: &Selection) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    let mut symlinks = vec![];
+    for entry in archive
+        .entries()
+        .context("Failed to read the tar archive")?
+    {
+        let mut entry = entry.context("Failed to read a tar archive entry")?;
+        let entry_path = entry
+            .path()
+            .context("Failed to read a tar archive entry path")?
+            .into_owned();
+        let Some(path) = selection.select(&entry_path)? else {
+            continue;
+        };
+        let dst = root.join(&path);
+        match entry.header().entry_type() {
+            EntryType::Directory => create_dir(root, &entry_path, &dst)?,
+            EntryType::Regular | EntryType::Continuous => {
+                prepare(root, &entry_path, &dst)?;
+                entry
+                    .unpack(&dst)
+                    .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+            }
+            EntryType::Symlink => {
+                let target = entry
+                    .link_name()
+                    .context("Failed to read a tar archive symlink target")?
+                    .ok_or_else(|| anyhow!("The symlink {} has no target", entry_path.display()))?
+                    .into_owned();
+                let parent = prepare(root, &entry_path, &dst)?;
+                check_symlink(root, &entry_path, parent, &target)?;
+                entry
+                    .unpack(&dst)
+                    .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+                symlinks.push(Symlink {
+                    entry_path,
+                    dst,
+                    target,
+                });
+            }
+            EntryType::Link => {
+                let target = entry
+                    .link_name()
+                    .context("Failed to read a tar archive hard link target")?
+                    .ok_or_else(|| anyhow!("The hard link {} has no target", entry_path.display()))?
+                    .into_owned();
+                let target = selection
+                    .strip(&target)?
+                    .map(|target| root.join(target))
+                    .ok_or_else(|| unsafe_entry(&entry_path))?;
+                prepare(root, &entry_path, &dst)?;
+                if !target
+                    .canonicalize()
+                    .is_ok_and(|target| target.starts_with(root))
+                {
+                    return Err(unsafe_entry(&entry_path));
+                }
+                std::fs::hard_link(&target, &dst)
+                    .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+            }
+            // N.B.: We do not extract device files, fifos and the like.
+            _ => {}
+        }
+    }
+    recheck_symlinks(root, &symlinks)
+}
+
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: &[u8; 4] = b"PK\x01\x02";
+
+fn unpack_zip(reader: &mut impl Read, root: &Path, selection: &Selection) -> Result<()> {
+    let mut extracted = HashMap::new();
+    let mut symlinks = vec![];
+    while let Some(mut file) =
+        zip::read::read_zipfile_from_stream(reader).context("Failed to read a zip archive entry")?
+    {
+        let entry_path = PathBuf::from(file.name());
+        let Some(path) = selection.select(&entry_path)? else {
+            continue;
+        };
+        let dst = root.join(&path);
+        if file.is_dir() {
+            create_dir(root, &entry_path, &dst)?;
+            continue;
+        }
+        prepare(root, &entry_path, &dst)?;
+        let mut output = File::create(&dst)
+            .with_context(|| format!("Failed to create the file {}", dst.display()))?;
+        std::io::copy(&mut file, &mut output)
+            .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+        extracted.insert(file.name_raw().to_vec(), (entry_path, path));
+    }
+
+    // N.B.: The unix modes of entries, which mark executables and symlinks, are only recorded in
+    // the central directory that follows the entries; so we apply them after the fact. The
+    // stream reader has already consumed the signature of the first central directory header.
+    loop {
+        let mut header = [0; 42];
+        reader
+            .read_exact(&mut header)
+            .context("Failed to read the zip archive central directory")?;
+        let field = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let mut name = vec![0; usize::from(field(24))];
+        reader
+            .read_exact(&mut name)
+            .context("Failed to read the zip archive central directory")?;
+        std::io::copy(
+            &mut reader.take(u64::from(field(26)) + u64::from(field(28))),
+            &mut std::io::sink(),
+        )
+        .context("Failed to read the zip archive central directory")?;
+        // The upper byte of "version made by" identifies the host system, where 3 is unix.
+        if header[1] == 3
+            && let Some((entry_path, path)) = extracted.get(&name)
+        {
+            let external_attributes =
+                u32::from_le_bytes([header[34], header[35], header[36], header[37]]);
+            if let Some(symlink) =
+                apply_unix_mode(root, entry_path, path, external_attributes >> 16)?
+            {
+                symlinks.push(symlink);
+            }
+        }
+        let mut signature = [0; 4];
+        if reader.read_exact(&mut signature).is_err()
+            || &signature != CENTRAL_DIRECTORY_HEADER_SIGNATURE
+        {
+            return recheck_symlinks(root, &symlinks);
+        }
+    }
+}
+
+/// Applies the unix mode of an extracted zip entry, turning it into a symlink if marked as one.
+///
+/// Returns the symlink created, if any.
+#[cfg(unix)]
+fn apply_unix_mode(
+    root: &Path,
+    entry_path: &Path,
+    path: &Path,
+    mode: u32,
+) -> Result<Option<Symlink>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    const S_IFMT: u32 = 0o170000;
+    const S_IFLNK: u32 = 0o120000;
+
+    let dst = root.join(path);
+    if mode & S_IFMT == S_IFLNK {
+        let target = PathBuf::from(
+            std::fs::read_to_string(&dst)
+                .with_context(|| format!("Failed to read the symlink {}", entry_path.display()))?,
+        );
+        let parent = prepare(root, entry_path, &dst)?;
+        check_symlink(root, entry_path, parent, &target)?;
+        std::os::unix::fs::symlink(&target, &dst)
+            .with_context(|| format!("Failed to extract {}", entry_path.display()))?;
+        Ok(Some(Symlink {
+            entry_path: entry_path.to_path_buf(),
+            dst,
+            target,
+        }))
+    } else {
+        if mode & 0o777 != 0 {
+            std::fs::set_permissions(&dst, std::fs::Permissions::from_mode(mode & 0o777))
+                .with_context(|| format!("Failed to set the mode of {}", entry_path.display()))?;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_unix_mode(
+    _root: &Path,
+    _entry_path: &Path,
+    _path: &Path,
+    _mode: u32,
+) -> Result<Option<Symlink>> {
+    Ok(None)
+}
+
+fn unpack(
+    archive_type: ArchiveType,
+    mut reader: impl Read,
+    root: &Path,
+    selection: &Selection,
+) -> Result<()> {
+    {
+        let mut decompressed = archive_type.decompress(&mut reader)?;
+        if archive_type == ArchiveType::Zip {
+            unpack_zip(&mut decompressed, root, selection)?;
+        } else {
+            unpack_tar(&mut decompressed, root, selection)?;
+        }
+        // N.B.: Archives can have trailing padding we need not read; but we drain it so the
+        // fetch writing to us does not fail.
+        std::io::copy(&mut decompressed, &mut std::io::sink())
+            .context("Failed to read the end of the archive")?;
+    }
+    std::io::copy(&mut reader, &mut std::io::sink())
+        .context("Failed to read the end of the archive")?;
+    Ok(())
+}
+
+/// Where and how to extract a fetched archive.
+pub(crate) struct Extraction {
+    pub(crate) dir: PathBuf,
+    pub(crate) archive_type: ArchiveType,
+    pub(crate) selection: Selection,
+    pub(crate) create_dirs: bool,
+}
+
+fn check_extraction_dir(dir: &Path) -> Result<()> {
+    let is_empty_dir = || {
+        dir.read_dir()
+            .is_ok_and(|mut entries| entries.next().is_none())
+    };
+    if dir.symlink_metadata().is_ok() && !is_empty_dir() {
+        return Err(anyhow::Error::new(Failure::new(
+            Category::Write,
+            format!(
+                "Refusing to extract into {dir} since it already exists and is not an empty \
+                directory",
+                dir = dir.display()
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// Fetches an archive, extracting it as it streams in without storing the archive itself.
+///
+/// The archive is extracted to a temporary directory alongside `extraction.dir` that is only
+/// renamed into place once the fetch and extraction both succeed.
+pub(crate) fn fetch_and_extract(
+    url: &str,
+    extraction: &Extraction,
+    options: &Options,
+) -> Result<Response> {
+    let dir = extraction.dir.as_path();
+    check_extraction_dir(dir)?;
+    let parent = parent_dir(dir);
+    if extraction.create_dirs {
+        std::fs::create_dir_all(parent).with_context(|| {
+            Failure::new(
+                Category::Write,
+                format!("Failed to create the directory {}", parent.display()),
+            )
+        })?;
+    }
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".ptex.").suffix(".tmp");
+    // N.B.: Temporary directories are private by default, but we want the directory to end up
+    // with the same permissions as any other newly created directory.
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o777));
+    let staging = builder.tempdir_in(parent).with_context(|| {
+        Failure::new(
+            Category::Write,
+            format!(
+                "Failed to create a temporary directory in {}",
+                parent.display()
+            ),
+        )
+    })?;
+    let pending = Pending::register(staging.path());
+    let root = staging
+        .path()
+        .canonicalize()
+        .context("Failed to resolve the temporary extraction directory")?;
+
+    let (reader, writer) = std::io::pipe().context("Failed to create a pipe to extract through")?;
+    let extractor = {
+        let archive_type = extraction.archive_type;
+        let selection = extraction.selection.clone();
+        std::thread::spawn(move || unpack(archive_type, reader, &root, &selection))
+    };
+    let fetched = fetch(url, writer, options, None);
+    let extracted = extractor
+        .join()
+        .unwrap_or_else(|_| Err(anyhow!("The archive extraction thread panicked")))
+        .with_context(|| {
+            Failure::new(
+                Category::Archive,
+                format!(
+                    "Failed to extract {url} as a {archive_type} archive",
+                    url = redact_url(url),
+                    archive_type = extraction.archive_type.name()
+                ),
+            )
+        });
+    let response = match fetched {
+        Ok(response) => response,
+        // N.B.: When extraction fails the fetch fails to write to the pipe; so we report the
+        // underlying extraction failure.
+        Err(err) if Category::of(&err) == Category::Write && extracted.is_err() => {
+            return extracted.map(|()| Response::default());
+        }
+        Err(err) => return Err(err),
+    };
+    if response.not_modified {
+        return Ok(response);
+    }
+    extracted?;
+
+    if dir.is_dir() {
+        std::fs::remove_dir(dir).with_context(|| {
+            Failure::new(
+                Category::Write,
+                format!("Failed to replace the empty directory {}", dir.display()),
+            )
+        })?;
+    }
+    std::fs::rename(staging.path(), dir).with_context(|| {
+        Failure::new(
+            Category::Write,
+            format!("Failed to move the extracted archive to {}", dir.display()),
+        )
+    })?;
+    let _ = staging.keep();
+    drop(pending);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::Path;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use super::{ArchiveType, Extraction, Selection};
+    use crate::error::Category;
+    use crate::fetch::Options;
+
+    fn append(builder: &mut tar::Builder<impl Write>, path: &[u8], link: Option<&[u8]>) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path);
+        if let Some(link) = link {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+            header.set_size(0);
+        } else if path.ends_with(b"/") {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+        } else {
+            header.set_size(7);
+        }
+        header.set_mode(0o755);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        let data: &[u8] = if link.is_some() || path.ends_with(b"/") {
+            b""
+        } else {
+            b"content"
+        };
+        builder.append(&header, data).unwrap();
+    }
+
+    fn tar_gz(entries: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, link) in entries {
+            append(&mut builder, path, *link);
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn extract(
+        archive: &[u8],
+        archive_type: ArchiveType,
+        selection: Selection,
+    ) -> (tempfile::TempDir, anyhow::Result<()>) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let archive_path = tempdir.path().join("archive");
+        std::fs::write(&archive_path, archive).unwrap();
+        let url = url::Url::from_file_path(&archive_path).unwrap();
+        let result = super::fetch_and_extract(
+            url.as_str(),
+            &Extraction {
+                dir: tempdir.path().join("dest"),
+                archive_type,
+                selection,
+                create_dirs: false,
+            },
+            &Options::default(),
+        )
+        .map(|_response| ());
+        (tempdir, result)
+    }
+
+    #[test]
+    fn archive_type() {
+        assert_eq!(Some(ArchiveType::TarGz), ArchiveType::parse("tgz"));
+        assert_eq!(None, ArchiveType::parse("gz"));
+        assert_eq!(
+            Some(ArchiveType::TarZst),
+            ArchiveType::from_file_name("tool-1.0.TAR.ZST")
+        );
+        assert_eq!(
+            Some(ArchiveType::Tar),
+            ArchiveType::from_file_name("tool-1.0.tar")
+        );
+        assert_eq!(None, ArchiveType::from_file_name("tool-1.0.gz"));
+    }
+
+    #[test]
+    fn extract_tar() {
+        let archive = tar_gz(&[
+            (b"tool-1.0/bin/tool", None),
+            (b"tool-1.0/bin/link", Some(b"tool")),
+            (b"tool-1.0/share/doc", None),
+            (b"README", None),
+        ]);
+        let (tempdir, result) = extract(
+            &archive,
+            ArchiveType::TarGz,
+            Selection {
+                strip_components: 1,
+                include: vec!["b?n".to_string()],
+            },
+        );
+        result.unwrap();
+        let dest = tempdir.path().join("dest");
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(dest.join("bin/tool")).unwrap()
+        );
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(dest.join("bin/link")).unwrap()
+        );
+        assert!(!dest.join("share").exists());
+        assert!(!dest.join("README").exists());
+    }
+
+    #[test]
+    fn extract_unsafe() {
+        let assert_refused = |entries: &[(&[u8], Option<&[u8]>)]| {
+            let (tempdir, result) =
+                extract(&tar_gz(entries), ArchiveType::TarGz, Selection::default());
+            let err = result.unwrap_err();
+            assert_eq!(Category::Archive, Category::of(&err));
+            assert!(
+                format!("{err:#}").contains("Refusing to extract the archive entry"),
+                "{err:#}"
+            );
+            assert!(!tempdir.path().join("dest").exists());
+            assert_eq!(
+                vec!["archive".to_string()],
+                std::fs::read_dir(tempdir.path())
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .collect::<Vec<_>>()
+            );
+        };
+        assert_refused(&[(b"../evil", None)]);
+        assert_refused(&[(b"/evil", None)]);
+        assert_refused(&[(b"dir/link", Some(b"../.."))]);
+        assert_refused(&[(b"link", Some(b"/etc"))]);
+        // N.B.: The symlink is safe on its own but the entry written through it is not.
+        assert_refused(&[
+            (b"a/b/link", Some(b"..")),
+            (b"a/b/link/link2", Some(b"../..")),
+        ]);
+        // N.B.: Each symlink resolves inside on its own text, but the second passes through the
+        // first.
+        assert_refused(&[
+            (b"p/q/l", Some(b"..")),
+            (b"m", Some(b"p/q/l/../..")),
+            (b"m/escaped_dir/", None),
+        ]);
+        assert_refused(&[
+            (b"x", Some(b".")),
+            (b"m", Some(b"x/..")),
+            (b"m/escaped", None),
+        ]);
+        assert_refused(&[(b"m", Some(b"x/..")), (b"x", Some(b"."))]);
+        // N.B.: The first symlink steps out of what is a file when it is extracted, but the file
+        // is replaced by a symlink afterwards.
+        assert_refused(&[
+            (b"p/q/l", None),
+            (b"m", Some(b"p/q/l/../..")),
+            (b"p/q/l", Some(b"..")),
+        ]);
+    }
+
+    #[test]
+    fn extract_zip() {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        writer
+            .start_file("tool-1.0/bin/tool", options.unix_permissions(0o755))
+            .unwrap();
+        writer.write_all(b"content").unwrap();
+        writer
+            .start_file("tool-1.0/README", options.unix_permissions(0o644))
+            .unwrap();
+        writer.write_all(b"readme").unwrap();
+        writer
+            .add_symlink("tool-1.0/link", "bin/tool", options)
+            .unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let (tempdir, result) = extract(
+            &archive,
+            ArchiveType::Zip,
+            Selection {
+                strip_components: 1,
+                include: vec![],
+            },
+        );
+        result.unwrap();
+        let dest = tempdir.path().join("dest");
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(dest.join("bin/tool")).unwrap()
+        );
+        assert_eq!(
+            b"readme".as_slice(),
+            std::fs::read(dest.join("README")).unwrap()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(0o755, mode(&dest.join("bin/tool")));
+            assert_eq!(0o644, mode(&dest.join("README")));
+            assert_eq!(
+                Path::new("bin/tool"),
+                std::fs::read_link(dest.join("link")).unwrap()
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extract_zip_unsafe() {
+        let assert_refused = |symlinks: &[(&str, &str)]| {
+            let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            let options = zip::write::SimpleFileOptions::default();
+            for (path, target) in symlinks {
+                writer.add_symlink(*path, *target, options).unwrap();
+            }
+            let archive = writer.finish().unwrap().into_inner();
+
+            let (tempdir, result) = extract(&archive, ArchiveType::Zip, Selection::default());
+            let err = result.unwrap_err();
+            assert_eq!(Category::Archive, Category::of(&err));
+            assert!(!tempdir.path().join("dest").exists());
+        };
+        assert_refused(&[("p/q/l", ".."), ("m", "p/q/l/../..")]);
+        // N.B.: Symlinks are only created once the central directory is read; so when the first
+        // is created the second is still a file.
+        assert_refused(&[("m", "p/q/l/../.."), ("p/q/l", "..")]);
+    }
+}
@@ -0,0 +1,1960 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use curl::easy::{Auth, Easy2, Handler, InfoType, List, NetRc, WriteError};
+use curl::multi::{Easy2Handle, Multi};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Fingerprint;
+use crate::error::{CURLE_PROXY, Category, Failure};
+use crate::events::{Event, Events};
+use crate::interrupt;
+use crate::redact::{redact_header, redact_url};
+use crate::trace::Trace;
+
+// N.B.: These match curl's own --retry backoff: starting at 1 second and doubling up to 10 minutes.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+/// Controls re-trying fetches that fail with transient errors.
+#[derive(Clone, Default)]
+pub(crate) struct Retry {
+    pub(crate) count: u32,
+    pub(crate) max_time: Option<Duration>,
+}
+
+/// Controls the use of credentials from ~/.netrc.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Netrc {
+    Ignored,
+    #[default]
+    Optional,
+    Required,
+}
+
+impl From<Netrc> for NetRc {
+    fn from(netrc: Netrc) -> Self {
+        match netrc {
+            Netrc::Ignored => NetRc::Ignored,
+            Netrc::Optional => NetRc::Optional,
+            Netrc::Required => NetRc::Required,
+        }
+    }
+}
+
+/// Explicit proxy configuration. Absent this, libcurl uses the standard proxy env vars like
+/// `https_proxy` and `NO_PROXY`.
+#[derive(Clone, Default, Deserialize)]
+pub(crate) struct Proxy {
+    pub(crate) url: Option<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) noproxy: Option<String>,
+    pub(crate) cacert: Option<PathBuf>,
+}
+
+impl Proxy {
+    fn configure<H>(&self, easy: &mut Easy2<H>) -> Result<()> {
+        if let Some(url) = self.url.as_deref() {
+            easy.proxy(url).with_context(|| {
+                format!(
+                    "Failed to configure the proxy as {proxy}",
+                    proxy = redact_url(url)
+                )
+            })?;
+        }
+        if let Some(user) = self.user.as_deref() {
+            let (username, password) = user.split_once(':').unwrap_or((user, ""));
+            easy.proxy_username(username)
+                .context("Failed to configure the proxy username")?;
+            easy.proxy_password(password)
+                .context("Failed to configure the proxy password")?;
+            easy.proxy_auth(Auth::new().basic(true).digest(true).ntlm(true))
+                .context("Failed to configure proxy authentication methods")?;
+        }
+        if let Some(noproxy) = self.noproxy.as_deref() {
+            easy.noproxy(noproxy)
+                .with_context(|| format!("Failed to configure the no proxy list as {noproxy}"))?;
+        }
+        if let Some(cacert) = self.cacert.as_deref() {
+            let cacert = cacert.to_str().with_context(|| {
+                format!(
+                    "The proxy CA certificate path {cacert} is not valid UTF-8",
+                    cacert = cacert.display()
+                )
+            })?;
+            easy.proxy_cainfo(cacert).with_context(|| {
+                format!("Failed to configure the proxy CA certificate {cacert}")
+            })?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.url
+            .as_deref()
+            .map(redact_url)
+            .unwrap_or_else(|| "the proxy".to_string())
+    }
+}
+
+// N.B.: This is empty unless a CA bundle was embedded at build time via PTEX_EMBED_CA_BUNDLE.
+const EMBEDDED_CA_BUNDLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ca-bundle.pem"));
+
+/// TLS trust and client identity configuration.
+#[derive(Clone, Default)]
+pub(crate) struct Tls {
+    pub(crate) cacert: Option<PathBuf>,
+    pub(crate) capath: Option<PathBuf>,
+    pub(crate) cert: Option<PathBuf>,
+    pub(crate) key: Option<PathBuf>,
+    pub(crate) pinned_pubkey: Option<String>,
+}
+
+impl Tls {
+    fn configure<H>(&self, easy: &mut Easy2<H>) -> Result<()> {
+        if let Some(cacert) = self.cacert.as_deref() {
+            easy.cainfo(cacert).with_context(|| {
+                format!(
+                    "Failed to configure the CA certificate bundle {cacert}",
+                    cacert = cacert.display()
+                )
+            })?;
+        }
+        if let Some(capath) = self.capath.as_deref() {
+            easy.capath(capath).with_context(|| {
+                format!(
+                    "Failed to configure the CA certificate directory {capath}",
+                    capath = capath.display()
+                )
+            })?;
+        }
+        if self.cacert.is_none() && self.capath.is_none() && !EMBEDDED_CA_BUNDLE.is_empty() {
+            easy.ssl_cainfo_blob(EMBEDDED_CA_BUNDLE)
+                .context("Failed to configure the embedded CA certificate bundle")?;
+        }
+        if let Some(cert) = self.cert.as_deref() {
+            easy.ssl_cert(cert).with_context(|| {
+                format!(
+                    "Failed to configure the client certificate {cert}",
+                    cert = cert.display()
+                )
+            })?;
+        }
+        if let Some(key) = self.key.as_deref() {
+            easy.ssl_key(key).with_context(|| {
+                format!(
+                    "Failed to configure the client private key {key}",
+                    key = key.display()
+                )
+            })?;
+        }
+        if let Some(pinned_pubkey) = self.pinned_pubkey.as_deref() {
+            easy.pinned_public_key(pinned_pubkey).with_context(|| {
+                format!("Failed to configure the pinned public key {pinned_pubkey}")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Limits on how long a fetch may take before it is aborted.
+#[derive(Clone, Default)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Option<Duration>,
+    pub(crate) max_time: Option<Duration>,
+    pub(crate) speed_limit: Option<u32>,
+    pub(crate) speed_time: Option<Duration>,
+}
+
+impl Timeouts {
+    fn configure<H>(&self, easy: &mut Easy2<H>) -> Result<()> {
+        if let Some(connect) = self.connect {
+            easy.connect_timeout(connect)
+                .context("Failed to configure the connect timeout")?;
+        }
+        if let Some(max_time) = self.max_time {
+            easy.timeout(max_time)
+                .context("Failed to configure the maximum fetch time")?;
+        }
+        // N.B.: These defaults match curl's: a speed limit without a time applies over 30 seconds
+        // and a time without a speed limit aborts transfers slower than 1 byte per second.
+        let (speed_limit, speed_time) = match (self.speed_limit, self.speed_time) {
+            (None, None) => return Ok(()),
+            (limit, time) => (limit.unwrap_or(1), time.unwrap_or(Duration::from_secs(30))),
+        };
+        easy.low_speed_limit(speed_limit)
+            .context("Failed to configure the low speed limit")?;
+        easy.low_speed_time(speed_time)
+            .context("Failed to configure the low speed time")?;
+        Ok(())
+    }
+}
+
+/// Conditions under which the server should skip sending content we already have.
+///
+/// When a condition is met the server responds with 304 Not Modified, which the fetch treats as
+/// success, fetching no content.
+#[derive(Clone, Default)]
+pub(crate) struct Conditions {
+    pub(crate) if_modified_since: Option<SystemTime>,
+    pub(crate) if_none_match: Option<String>,
+}
+
+impl Conditions {
+    fn headers(&self) -> Vec<String> {
+        let mut headers = vec![];
+        if let Some(time) = self.if_modified_since {
+            headers.push(format!(
+                "If-Modified-Since: {date}",
+                date = httpdate::fmt_http_date(time)
+            ));
+        }
+        if let Some(etag) = self.if_none_match.as_deref() {
+            headers.push(format!("If-None-Match: {etag}"));
+        }
+        headers
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Options {
+    pub(crate) headers: Vec<String>,
+    pub(crate) show_headers: bool,
+    pub(crate) show_progress: bool,
+    pub(crate) retry: Retry,
+    pub(crate) netrc: Netrc,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) proxy: Proxy,
+    pub(crate) tls: Tls,
+    pub(crate) conditions: Conditions,
+    pub(crate) compressed: bool,
+    pub(crate) limit_rate: Option<u64>,
+    pub(crate) events: Option<Events>,
+    /// Print progress as lines of text at this interval instead of drawing a bar.
+    pub(crate) progress_lines: Option<ProgressInterval>,
+    pub(crate) trace: Trace,
+}
+
+/// How often to print a line of progress for a fetch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProgressInterval {
+    Every(Duration),
+    /// Every time the fetch completes another step of this many percent. Fetches of unknown size
+    /// fall back to `DEFAULT_PROGRESS_INTERVAL`.
+    Percent(u8),
+}
+
+pub(crate) const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tracks when to print the next line of progress.
+struct ProgressLines {
+    interval: ProgressInterval,
+    last_line_at: Instant,
+    last_step: u64,
+}
+
+impl ProgressLines {
+    fn new(interval: ProgressInterval) -> Self {
+        Self {
+            interval,
+            last_line_at: Instant::now(),
+            last_step: 0,
+        }
+    }
+
+    fn due(&mut self, bytes: u64, total: Option<u64>) -> bool {
+        let due = match (self.interval, total) {
+            (ProgressInterval::Percent(percent), Some(total)) => {
+                let step = bytes * 100 / total.max(1) / u64::from(percent.max(1));
+                step > std::mem::replace(&mut self.last_step, step)
+            }
+            (ProgressInterval::Every(interval), _) => self.last_line_at.elapsed() >= interval,
+            (ProgressInterval::Percent(_), None) => {
+                self.last_line_at.elapsed() >= DEFAULT_PROGRESS_INTERVAL
+            }
+        };
+        if due {
+            self.last_line_at = Instant::now();
+        }
+        due
+    }
+}
+
+/// Formats a duration tersely; e.g.: 18s, 4m05s or 1h02m.
+fn terse_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3600 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// The content encodings we ask for with --compressed, in order of preference.
+const ACCEPT_ENCODING: &str = "zstd, br, gzip, deflate";
+
+/// Decodes a response body sent with a `Content-Encoding`.
+///
+/// N.B.: The curl we link has neither zstd nor brotli support; so we decode all the encodings we
+/// ask for ourselves instead of leaving some to curl.
+enum Decoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    // A deflate body whose first bytes have not arrived yet to tell which kind it is.
+    Deflate(Vec<u8>),
+    Zlib(flate2::write::ZlibDecoder<Vec<u8>>),
+    RawDeflate(flate2::write::DeflateDecoder<Vec<u8>>),
+    Brotli(Box<brotli_decompressor::DecompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+/// The buffer size to decode brotli with, which is the same as its reader's default.
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+impl Decoder {
+    /// Returns a decoder for the given `Content-Encoding` or `None` if the content is not encoded.
+    fn new(encoding: &str) -> std::io::Result<Option<Self>> {
+        let decoder = match encoding.trim().to_ascii_lowercase().as_str() {
+            "identity" => return Ok(None),
+            "gzip" | "x-gzip" => Decoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            "deflate" => Decoder::Deflate(Vec::new()),
+            "br" => Decoder::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+            ))),
+            "zstd" => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "the server sent content with the unsupported encoding {encoding}"
+                )));
+            }
+        };
+        Ok(Some(decoder))
+    }
+
+    /// Picks the decoder for a deflate body given its first bytes.
+    ///
+    /// N.B.: A deflate `Content-Encoding` is meant to be zlib wrapped, but like curl we also accept
+    /// the raw deflate streams some servers send instead.
+    fn inflate(header: &[u8]) -> Self {
+        let is_zlib = header.len() >= 2
+            && header[0] & 0x0f == 8
+            && u16::from_be_bytes([header[0], header[1]]).is_multiple_of(31);
+        if is_zlib {
+            Decoder::Zlib(flate2::write::ZlibDecoder::new(Vec::new()))
+        } else {
+            Decoder::RawDeflate(flate2::write::DeflateDecoder::new(Vec::new()))
+        }
+    }
+
+    /// Decodes the next chunk of the body, returning the content decoded so far.
+    fn decode(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let decoded = match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            Decoder::Deflate(header) => {
+                header.extend_from_slice(data);
+                if header.len() < 2 {
+                    return Ok(Vec::new());
+                }
+                let header = std::mem::take(header);
+                *self = Decoder::inflate(&header);
+                return self.decode(&header);
+            }
+            Decoder::Zlib(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            Decoder::RawDeflate(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            Decoder::Brotli(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(decoded))
+    }
+
+    /// Finishes decoding the body, returning any remaining content.
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Deflate(header) if header.is_empty() => Ok(header),
+            Decoder::Deflate(header) => {
+                let mut decoder = Decoder::inflate(&header);
+                let mut decoded = decoder.decode(&header)?;
+                decoded.extend(decoder.finish()?);
+                Ok(decoded)
+            }
+            Decoder::Zlib(decoder) => decoder.finish(),
+            Decoder::RawDeflate(decoder) => decoder.finish(),
+            Decoder::Brotli(mut decoder) => {
+                decoder.close()?;
+                Ok(decoder.into_inner().unwrap_or_else(|output| output))
+            }
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+struct Verifier {
+    expected: Fingerprint,
+    size: u64,
+    hasher: Sha256,
+}
+
+impl Verifier {
+    fn new(expected: Fingerprint) -> Self {
+        Self {
+            expected,
+            size: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.size += data.len() as u64;
+        if self.expected.hash.is_some() {
+            self.hasher.update(data);
+        }
+    }
+
+    fn verify(&mut self, url: &str) -> Result<()> {
+        let url = redact_url(url);
+        if let Some(expected_size) = self.expected.size
+            && expected_size != self.size
+        {
+            return Err(anyhow::Error::new(Failure::new(
+                Category::Integrity,
+                format!(
+                    "Size mismatch for {url}: expected {expected_size} bytes but fetched {size} \
+                    bytes.",
+                    size = self.size
+                ),
+            )));
+        }
+        if let Some(expected_hash) = self.expected.hash.as_deref() {
+            let hash = hex::encode(std::mem::take(&mut self.hasher).finalize());
+            if !expected_hash.eq_ignore_ascii_case(&hash) {
+                return Err(anyhow::Error::new(Failure::new(
+                    Category::Integrity,
+                    format!(
+                        "Hash mismatch for {url}: expected sha256 {expected_hash} but fetched \
+                        content has sha256 {hash}."
+                    ),
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The minimum interval between progress events.
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+struct FetchHandler<W: Write> {
+    output: W,
+    progress: ProgressBar,
+    events: Option<Events>,
+    // The URL originally requested with any credentials redacted, which identifies the fetch in
+    // events and progress output.
+    url: String,
+    // The redacted URL of the current request after following any redirects.
+    current_url: String,
+    // When the first attempt began.
+    start: Option<Instant>,
+    attempt_start: Instant,
+    last_progress_event: Option<Instant>,
+    progress_lines: Option<ProgressLines>,
+    show_headers: bool,
+    trace: Trace,
+    verifier: Option<Verifier>,
+    // Whether to decode the content encoding we asked for with --compressed.
+    decode: bool,
+    decoder: Option<Decoder>,
+    decode_error: Option<std::io::Error>,
+    // The total number of bytes written to the output across all attempts.
+    written: u64,
+    // The byte offset into the remote file the current attempt started at.
+    offset: u64,
+    // The number of leading bytes of the current response to discard since they were already
+    // written by a prior attempt.
+    skip: u64,
+    headers: ResponseHeaders,
+    // The validator of the response whose body is being written, if any.
+    validator: Option<String>,
+    body_started: bool,
+    write_error: Option<std::io::Error>,
+}
+
+/// The response headers we act on, as parsed from the current response.
+#[derive(Default)]
+struct ResponseHeaders {
+    retry_after: Option<Duration>,
+    content_disposition: Option<String>,
+    last_modified: Option<SystemTime>,
+    etag: Option<String>,
+    content_encoding: Option<String>,
+    status: Option<u32>,
+    location: Option<String>,
+}
+
+impl ResponseHeaders {
+    fn parse(&mut self, name: &str, value: &str) {
+        if name.eq_ignore_ascii_case("Retry-After") {
+            self.retry_after = parse_retry_after(value);
+        } else if name.eq_ignore_ascii_case("Content-Disposition") {
+            self.content_disposition = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("Last-Modified") {
+            self.last_modified = httpdate::parse_http_date(value).ok();
+        } else if name.eq_ignore_ascii_case("ETag") {
+            self.etag = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("Content-Encoding") {
+            self.content_encoding = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("Location") {
+            self.location = Some(value.to_string());
+        }
+    }
+
+    /// Returns a validator that identifies the version of the file being fetched and is suitable
+    /// for use in an If-Range header.
+    fn validator(&self) -> Option<String> {
+        // N.B.: Weak ETags cannot be used with If-Range.
+        match self.etag.as_deref() {
+            Some(etag) if !etag.starts_with("W/") => Some(etag.to_string()),
+            _ => self.last_modified.map(httpdate::fmt_http_date),
+        }
+    }
+}
+
+#[cfg(target_family = "windows")]
+const NEWLINE: &str = "\r\n";
+
+#[cfg(target_family = "unix")]
+const NEWLINE: &str = "\n";
+
+fn write(state: &ProgressState, w: &mut dyn std::fmt::Write) {
+    write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+}
+
+impl<W: Write> FetchHandler<W> {
+    fn new(
+        url: &str,
+        output: W,
+        options: &Options,
+        progress: Option<ProgressBar>,
+        fingerprint: Option<Fingerprint>,
+    ) -> Self {
+        let progress = if let Some(progress) = progress {
+            progress.set_prefix(format!(
+                "Downloading {url}...{NEWLINE}",
+                url = redact_url(url)
+            ));
+            // N.B.: A throttled fetch can look stalled; so we show its rate against the limit.
+            let rate = options
+                .limit_rate
+                .map(|limit| {
+                    format!(
+                        " at {{binary_bytes_per_sec}} (limited to {limit}/s)",
+                        limit = HumanBytes(limit)
+                    )
+                })
+                .unwrap_or_default();
+            progress.set_style(
+                ProgressStyle::with_template(&format!(
+                    "{{prefix}}[{{elapsed_precise}}] [{{bar:30}}] {{bytes}}/{{total_bytes}}{{msg}}\
+                    {rate} (eta: {{eta}})"
+                ))
+                .expect("The template string is known-good.")
+                .with_key("eta", write)
+                .progress_chars("#>-"),
+            );
+            progress
+        } else {
+            ProgressBar::hidden()
+        };
+        Self {
+            output,
+            progress,
+            events: options.events.clone(),
+            url: redact_url(url),
+            current_url: redact_url(url),
+            start: None,
+            attempt_start: Instant::now(),
+            last_progress_event: None,
+            progress_lines: options.progress_lines.map(ProgressLines::new),
+            show_headers: options.show_headers,
+            trace: options.trace.clone(),
+            verifier: fingerprint.map(Verifier::new),
+            decode: false,
+            decoder: None,
+            decode_error: None,
+            written: 0,
+            offset: 0,
+            skip: 0,
+            headers: ResponseHeaders::default(),
+            validator: None,
+            body_started: false,
+            write_error: None,
+        }
+    }
+
+    fn begin_attempt(&mut self, resume_from: u64) {
+        if self.start.is_none() {
+            self.start = Some(Instant::now());
+            self.emit(&Event::Start {
+                url: &self.url,
+                size: self
+                    .verifier
+                    .as_ref()
+                    .and_then(|verifier| verifier.expected.size),
+            });
+        }
+        self.offset = resume_from;
+        self.skip = self.written - resume_from;
+        self.headers = ResponseHeaders::default();
+        self.body_started = false;
+        self.decoder = None;
+        self.current_url = self.url.clone();
+        self.attempt_start = Instant::now();
+    }
+
+    /// Writes the next chunk of decoded content to the output, skipping any already written by a
+    /// prior attempt.
+    fn consume(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let skip = self.skip.min(data.len() as u64);
+        self.skip -= skip;
+        let chunk = &data[skip as usize..];
+        self.output.write_all(chunk)?;
+        if let Some(verifier) = self.verifier.as_mut() {
+            verifier.update(chunk);
+        }
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Writes out any content still buffered in the decoder once the body is complete.
+    fn finish_decoding(&mut self) -> Result<()> {
+        if let Some(decoder) = self.decoder.take() {
+            let decoded = decoder.finish().map_err(|err| {
+                Failure::new(
+                    Category::Network,
+                    format!("Failed to decode the fetched content: {err}"),
+                )
+            })?;
+            self.consume(&decoded).map_err(|err| {
+                Failure::new(
+                    Category::Write,
+                    format!("Failed to write fetched content: {err}"),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn emit(&self, event: &Event) {
+        if let Some(events) = self.events.as_ref() {
+            events.emit(event);
+        }
+    }
+
+    /// Reports the outcome of the fetch.
+    fn finish(&self, result: &Result<Response>) {
+        if self.progress_lines.is_some()
+            && let Ok(response) = result
+            && !response.not_modified
+        {
+            let elapsed = self.start.map(|start| start.elapsed()).unwrap_or_default();
+            eprintln!(
+                "Downloaded {url}: {bytes} in {elapsed}.",
+                url = self.url,
+                bytes = HumanBytes(self.written),
+                elapsed = terse_duration(elapsed)
+            );
+        }
+        match result {
+            Ok(response) => self.emit(&Event::Complete {
+                url: &self.url,
+                effective_url: &redact_url(&response.effective_url),
+                bytes: self.written,
+                not_modified: response.not_modified,
+            }),
+            Err(err) => self.emit(&Event::Error {
+                url: &self.url,
+                error: &format!("{err:#}"),
+                exit_code: Category::of(err).exit_code(),
+            }),
+        }
+    }
+
+    fn redirected(&mut self) {
+        let (Some(status @ 300..=399), Some(location)) =
+            (self.headers.status, self.headers.location.as_deref())
+        else {
+            return;
+        };
+        // N.B.: A Location may be relative to the URL that was redirected.
+        let to = redact_url(
+            &url::Url::parse(&self.current_url)
+                .and_then(|base| base.join(location))
+                .map(String::from)
+                .unwrap_or_else(|_| location.to_string()),
+        );
+        self.emit(&Event::Redirect {
+            url: &self.url,
+            status,
+            from: &self.current_url,
+            to: &to,
+        });
+        self.current_url = to;
+    }
+}
+
+/// Splits a raw header line into its trimmed name and value.
+fn parse_header(data: &[u8]) -> Option<(&str, &str)> {
+    let (name, value) = std::str::from_utf8(data).ok()?.split_once(':')?;
+    Some((name.trim(), value.trim()))
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+impl<W: Write> Handler for FetchHandler<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        if !self.body_started {
+            self.body_started = true;
+            self.validator = self.headers.validator();
+            if self.decode
+                && let Some(encoding) = self.headers.content_encoding.as_deref()
+            {
+                match Decoder::new(encoding) {
+                    Ok(decoder) => self.decoder = decoder,
+                    Err(err) => {
+                        self.decode_error = Some(err);
+                        // N.B.: Returning a short count aborts the transfer with
+                        // CURLE_WRITE_ERROR.
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+        let decoded = match self.decoder.as_mut().map(|decoder| decoder.decode(data)) {
+            Some(Ok(decoded)) => Some(decoded),
+            Some(Err(err)) => {
+                self.decode_error = Some(err);
+                return Ok(0);
+            }
+            None => None,
+        };
+        if let Err(err) = self.consume(decoded.as_deref().unwrap_or(data)) {
+            self.write_error = Some(err);
+            return Ok(0);
+        }
+        Ok(data.len())
+    }
+
+    fn header(&mut self, data: &[u8]) -> bool {
+        if self.show_headers
+            && let Ok(header) = std::str::from_utf8(data)
+        {
+            eprint!("{header}", header = redact_header(header));
+        }
+        if data.starts_with(b"HTTP/") {
+            // A new response (e.g.: after a redirect) is starting.
+            self.headers = ResponseHeaders {
+                status: std::str::from_utf8(data)
+                    .ok()
+                    .and_then(|status_line| status_line.split_whitespace().nth(1))
+                    .and_then(|code| code.parse().ok()),
+                ..Default::default()
+            };
+            self.body_started = false;
+        } else if data == b"\r\n" || data == b"\n" {
+            self.redirected();
+        } else if let Some((name, value)) = parse_header(data) {
+            self.headers.parse(name, value);
+        }
+        true
+    }
+
+    fn debug(&mut self, kind: InfoType, data: &[u8]) {
+        let trace = &self.trace;
+        self.progress.suspend(|| trace.log(kind, data));
+    }
+
+    fn progress(&mut self, dltotal: f64, dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
+        if dltotal > 0.0 {
+            self.progress.set_length(self.offset + dltotal as u64)
+        }
+        self.progress.set_position(self.offset + dlnow as u64);
+        // N.B.: Curl reports the bytes received over the wire; so when the content is being
+        // decoded we separately report the decoded bytes written to the output.
+        if self.decode
+            && let Some(encoding) = self.headers.content_encoding.as_deref()
+        {
+            self.progress.set_message(format!(
+                " ({written} decoded from {encoding})",
+                written = HumanBytes(self.written)
+            ));
+        }
+        if let Some(lines) = self.progress_lines.as_mut() {
+            let bytes = self.offset + dlnow as u64;
+            let total = (dltotal > 0.0).then_some(self.offset + dltotal as u64);
+            if lines.due(bytes, total) {
+                let elapsed = self.attempt_start.elapsed().as_secs_f64();
+                let rate = if elapsed > 0.0 { dlnow / elapsed } else { 0.0 };
+                let mut line = format!("Downloading {url}:", url = self.url);
+                if let Some(total) = total {
+                    line.push_str(&format!(
+                        " {percent}% {bytes}/{total}",
+                        percent = bytes * 100 / total.max(1),
+                        bytes = HumanBytes(bytes),
+                        total = HumanBytes(total)
+                    ));
+                } else {
+                    line.push_str(&format!(" {bytes}", bytes = HumanBytes(bytes)));
+                }
+                line.push_str(&format!(" {rate}/s", rate = HumanBytes(rate as u64)));
+                if let Some(total) = total
+                    && rate > 0.0
+                {
+                    line.push_str(&format!(
+                        " eta {eta}",
+                        eta = terse_duration(Duration::from_secs_f64(
+                            total.saturating_sub(bytes) as f64 / rate
+                        ))
+                    ));
+                }
+                eprintln!("{line}");
+            }
+        }
+        if self.events.is_some()
+            && self
+                .last_progress_event
+                .is_none_or(|last| last.elapsed() >= PROGRESS_EVENT_INTERVAL)
+        {
+            self.last_progress_event = Some(Instant::now());
+            let elapsed = self.attempt_start.elapsed().as_secs_f64();
+            self.emit(&Event::Progress {
+                url: &self.url,
+                bytes: self.offset + dlnow as u64,
+                total: (dltotal > 0.0).then_some(self.offset + dltotal as u64),
+                rate: if elapsed > 0.0 {
+                    (dlnow / elapsed) as u64
+                } else {
+                    0
+                },
+            });
+        }
+        // N.B.: Returning false aborts the transfer.
+        !interrupt::interrupted()
+    }
+}
+
+fn is_retryable_status(code: u32) -> bool {
+    matches!(code, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+fn is_retryable(err: &curl::Error, response_code: u32, connect_code: u32) -> bool {
+    if connect_code >= 300 {
+        is_retryable_status(connect_code)
+    } else if err.is_http_returned_error() {
+        is_retryable_status(response_code)
+    } else {
+        err.is_operation_timedout()
+            || err.is_couldnt_connect()
+            || err.is_send_error()
+            || err.is_recv_error()
+            || err.is_partial_file()
+            || err.is_got_nothing()
+            || err.is_http2_error()
+            || err.is_http2_stream_error()
+    }
+}
+
+/// Categorizes a failed fetch attempt, distinguishing failures talking to the proxy from failures
+/// talking to the origin server and explaining timeouts and HTTP error statuses.
+fn fetch_error<W: Write>(
+    err: curl::Error,
+    easy: &mut Easy2<FetchHandler<W>>,
+    proxy: &Proxy,
+) -> anyhow::Error {
+    let connect_code = easy.http_connectcode().unwrap_or_default();
+    let response_code = easy.response_code().unwrap_or_default();
+    let failure = if connect_code >= 300 {
+        Some(Failure::new(
+            Category::Proxy,
+            format!(
+                "The proxy CONNECT request to {proxy} failed with HTTP status {connect_code}",
+                proxy = proxy.describe()
+            ),
+        ))
+    } else if err.is_couldnt_resolve_proxy() {
+        Some(Failure::new(
+            Category::ProxyResolve,
+            format!("Failed to resolve {proxy}", proxy = proxy.describe()),
+        ))
+    } else if err.code() == CURLE_PROXY {
+        Some(Failure::new(
+            Category::Proxy,
+            format!("Failed to negotiate with {proxy}", proxy = proxy.describe()),
+        ))
+    } else if err.is_aborted_by_callback() && interrupt::interrupted() {
+        Some(Failure::new(
+            Category::Interrupted,
+            "The fetch was interrupted",
+        ))
+    } else if err.is_operation_timedout() {
+        Some(Failure::new(
+            Category::Timeout,
+            "The fetch timed out (see the --connect-timeout, --max-time, --speed-limit and \
+            --speed-time options)",
+        ))
+    } else if err.is_http_returned_error() {
+        Some(Failure::new(
+            if is_retryable_status(response_code) {
+                Category::TransientHttpStatus
+            } else {
+                Category::HttpStatus
+            },
+            format!("The server responded with HTTP status {response_code}"),
+        ))
+    } else if err.is_write_error()
+        && let Some(decode_error) = easy.get_mut().decode_error.take()
+    {
+        Some(Failure::new(
+            Category::Network,
+            format!("Failed to decode the fetched content: {decode_error}"),
+        ))
+    } else if err.is_write_error()
+        && let Some(write_error) = easy.get_mut().write_error.take()
+    {
+        Some(Failure::new(
+            Category::Write,
+            format!("Failed to write fetched content: {write_error}"),
+        ))
+    } else {
+        None
+    };
+    let err = anyhow::Error::new(err);
+    match failure {
+        Some(failure) => err.context(failure),
+        None => err,
+    }
+}
+
+/// Details of the final response of a successful fetch.
+#[derive(Debug, Default)]
+pub(crate) struct Response {
+    /// The URL the content was ultimately fetched from after following any redirects.
+    pub(crate) effective_url: String,
+    /// Whether the server responded 304 Not Modified to a conditional fetch; in which case no
+    /// content was fetched.
+    pub(crate) not_modified: bool,
+    pub(crate) content_disposition: Option<String>,
+    pub(crate) last_modified: Option<SystemTime>,
+    pub(crate) etag: Option<String>,
+    pub(crate) report: Report,
+}
+
+/// Statistics curl gathered about the final attempt at a fetch.
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    /// The final HTTP status code; 0 for protocols without one, like file://.
+    pub(crate) response_code: u32,
+    pub(crate) redirect_count: u32,
+    pub(crate) content_type: Option<String>,
+    pub(crate) remote_ip: Option<String>,
+    pub(crate) remote_port: Option<u16>,
+    /// The number of bytes received, before any content decoding.
+    pub(crate) download_size: u64,
+    pub(crate) namelookup_time: Duration,
+    pub(crate) connect_time: Duration,
+    pub(crate) appconnect_time: Duration,
+    pub(crate) pretransfer_time: Duration,
+    pub(crate) starttransfer_time: Duration,
+    pub(crate) total_time: Duration,
+}
+
+impl Report {
+    fn new<H>(easy: &Easy2<H>) -> Self {
+        Self {
+            response_code: easy.response_code().unwrap_or_default(),
+            redirect_count: easy.redirect_count().unwrap_or_default(),
+            content_type: easy.content_type().ok().flatten().map(str::to_string),
+            remote_ip: easy
+                .primary_ip()
+                .ok()
+                .flatten()
+                .filter(|ip| !ip.is_empty())
+                .map(str::to_string),
+            remote_port: easy.primary_port().ok().filter(|port| *port != 0),
+            download_size: easy.download_size().unwrap_or_default() as u64,
+            namelookup_time: easy.namelookup_time().unwrap_or_default(),
+            connect_time: easy.connect_time().unwrap_or_default(),
+            appconnect_time: easy.appconnect_time().unwrap_or_default(),
+            pretransfer_time: easy.pretransfer_time().unwrap_or_default(),
+            starttransfer_time: easy.starttransfer_time().unwrap_or_default(),
+            total_time: easy.total_time().unwrap_or_default(),
+        }
+    }
+
+    /// The average download rate in bytes per second.
+    pub(crate) fn download_speed(&self) -> u64 {
+        let seconds = self.total_time.as_secs_f64();
+        if seconds > 0.0 {
+            (self.download_size as f64 / seconds) as u64
+        } else {
+            0
+        }
+    }
+}
+
+/// The progress of a download that a later run of ptex can resume.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Resume {
+    /// The number of bytes of the file already fetched.
+    pub(crate) offset: u64,
+    /// An ETag or Last-Modified date identifying the version of the file the fetched bytes belong
+    /// to.
+    pub(crate) validator: Option<String>,
+}
+
+/// The server would not resume a download from where a prior run left off; either because the
+/// file has changed since or because the server does not support byte ranges.
+#[derive(Debug)]
+pub(crate) struct ResumeRejected;
+
+impl Display for ResumeRejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The server would not resume the partial download")
+    }
+}
+
+impl std::error::Error for ResumeRejected {}
+
+pub(crate) fn fetch<W: Write>(
+    url: &str,
+    output: W,
+    options: &Options,
+    fingerprint: Option<Fingerprint>,
+) -> Result<Response> {
+    fetch_from(url, output, options, fingerprint, None)
+}
+
+/// Fetches the remainder of a partially downloaded file, appending it to `output`.
+///
+/// The `resume` state is updated to reflect the bytes fetched even if the fetch fails; so a later
+/// fetch can resume from there. If the server cannot resume the download, a `ResumeRejected`
+/// error is returned and the caller should truncate the output and start over.
+pub(crate) fn fetch_resumable<W: Write>(
+    url: &str,
+    output: W,
+    options: &Options,
+    resume: &mut Resume,
+) -> Result<Response> {
+    fetch_from(url, output, options, None, Some(resume))
+}
+
+fn fetch_from<W: Write>(
+    url: &str,
+    output: W,
+    options: &Options,
+    fingerprint: Option<Fingerprint>,
+    resume: Option<&mut Resume>,
+) -> Result<Response> {
+    let mut easy = configure(
+        url,
+        output,
+        options,
+        fingerprint,
+        resume.as_deref(),
+        ProgressBar::no_length,
+    )?;
+    let result = perform(&mut easy, url, options, resume.is_some());
+    if let Some(resume) = resume {
+        let handler = easy.get_ref();
+        resume.offset = handler.written;
+        resume.validator = handler.validator.clone();
+    }
+    let result = result.and_then(|()| response(&mut easy, url));
+    easy.get_ref().finish(&result);
+    result
+}
+
+/// Sets up a fetch of `url` into `output`, drawing its progress on the bar made by `new_bar` if
+/// progress is being shown.
+fn configure<W: Write>(
+    url: &str,
+    output: W,
+    options: &Options,
+    fingerprint: Option<Fingerprint>,
+    resume: Option<&Resume>,
+    new_bar: impl FnOnce() -> ProgressBar,
+) -> Result<Easy2<FetchHandler<W>>> {
+    let mut easy = Easy2::new(FetchHandler::new(
+        url,
+        output,
+        options,
+        options.show_progress.then(new_bar),
+        fingerprint,
+    ));
+    easy.follow_location(true)
+        .context("Failed to configure re-direct following")?;
+    easy.fail_on_error(true)
+        .context("Failed to configure fail on error behavior")?;
+    easy.netrc(options.netrc.into())
+        .context("Failed to configure ~/.netrc parsing")?;
+    easy.url(url).with_context(|| {
+        format!(
+            "Failed to configure URL to fetch from as {url}",
+            url = redact_url(url)
+        )
+    })?;
+    easy.progress(true)
+        .context("Failed to enable progress meter")?;
+    easy.useragent(format!("ptex/{version}", version = env!("CARGO_PKG_VERSION")).as_str())
+        .context("Failed to set User-Agent")?;
+    options.timeouts.configure(&mut easy)?;
+    options.proxy.configure(&mut easy)?;
+    options.tls.configure(&mut easy)?;
+    if options.trace.enabled() {
+        easy.verbose(true)
+            .context("Failed to enable verbose logging")?;
+    }
+    if let Some(limit_rate) = options.limit_rate {
+        easy.max_recv_speed(limit_rate)
+            .context("Failed to configure the download rate limit")?;
+    }
+    // N.B.: Byte offsets into a partial download refer to the decoded content; so we only
+    // negotiate a content encoding when we're not resuming one.
+    if options.compressed && resume.is_none() {
+        easy.accept_encoding(ACCEPT_ENCODING)
+            .context("Failed to configure content negotiation")?;
+        easy.http_content_decoding(false)
+            .context("Failed to configure content decoding")?;
+        easy.get_mut().decode = true;
+    }
+    let mut extra_headers = options.conditions.headers();
+    if let Some(resume) = resume {
+        easy.get_mut().written = resume.offset;
+        easy.get_mut().validator = resume.validator.clone();
+        if let Some(validator) = resume.validator.as_deref() {
+            // N.B.: This asks the server to send the whole file instead of the requested range if
+            // it has changed since the partial download; which we then treat as a rejection.
+            extra_headers.push(format!("If-Range: {validator}"));
+        }
+    }
+    if !options.headers.is_empty() || !extra_headers.is_empty() {
+        let mut curl_headers = List::new();
+        for header in options.headers.iter().chain(&extra_headers) {
+            curl_headers
+                .append(header)
+                .with_context(|| format!("Failed to set custom header {header}"))?;
+        }
+        easy.http_headers(curl_headers)
+            .context("Failed to configure custom headers")?;
+    }
+    Ok(easy)
+}
+
+/// Verifies a completed fetch and describes its response.
+fn response<W: Write>(easy: &mut Easy2<FetchHandler<W>>, url: &str) -> Result<Response> {
+    easy.get_mut().finish_decoding()?;
+    let not_modified = easy.response_code().unwrap_or_default() == 304;
+    if !not_modified && let Some(verifier) = easy.get_mut().verifier.as_mut() {
+        verifier.verify(url)?;
+    }
+    let effective_url = easy
+        .effective_url()
+        .ok()
+        .flatten()
+        .unwrap_or(url)
+        .to_string();
+    let headers = std::mem::take(&mut easy.get_mut().headers);
+    Ok(Response {
+        effective_url,
+        not_modified,
+        content_disposition: headers.content_disposition,
+        last_modified: headers.last_modified,
+        etag: headers.etag,
+        report: Report::new(easy),
+    })
+}
+
+/// Tracks the attempts made at a fetch, deciding which failures to retry and from where.
+struct Attempts {
+    start: Instant,
+    retries_left: u32,
+    delay: Duration,
+    resume_from: u64,
+    // If set, the output already holds bytes from a prior run that cannot be discarded by
+    // skipping; so a server that will not resume is reported as `ResumeRejected`.
+    resumed: bool,
+    use_ranges: bool,
+}
+
+impl Attempts {
+    fn new(options: &Options, resume_from: u64, resumed: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            retries_left: options.retry.count,
+            delay: INITIAL_RETRY_DELAY,
+            resume_from,
+            resumed,
+            // N.B.: Byte ranges address the encoded content; so when the content may be encoded
+            // we restart failed attempts from the beginning, skipping the decoded bytes already
+            // written.
+            use_ranges: resumed || !options.compressed,
+        }
+    }
+
+    fn begin<W: Write>(&self, easy: &mut Easy2<FetchHandler<W>>) -> Result<()> {
+        easy.get_mut().begin_attempt(self.resume_from);
+        easy.resume_from(self.resume_from)
+            .context("Failed to configure the offset to resume fetching from")
+    }
+
+    /// How long to wait before the next attempt, honoring any Retry-After the server sent.
+    ///
+    /// N.B.: The wait a server asks for is capped like our own backoff; so a Retry-After of a day
+    /// does not stall the fetch for that long.
+    fn wait(&self, retry_after: Option<Duration>) -> Duration {
+        retry_after.map_or(self.delay, |retry_after| retry_after.min(MAX_RETRY_DELAY))
+    }
+
+    /// Decides how to handle a failed attempt: either returning how long to wait before the next
+    /// attempt or the error to fail the fetch with.
+    fn failed<W: Write>(
+        &mut self,
+        easy: &mut Easy2<FetchHandler<W>>,
+        err: curl::Error,
+        url: &str,
+        options: &Options,
+    ) -> Result<Duration> {
+        let url = &redact_url(url);
+        let response_code = easy.response_code().unwrap_or_default();
+        if self.resumed
+            && self.resume_from > 0
+            && (err.is_range_error() || (err.is_http_returned_error() && response_code == 416))
+        {
+            return Err(ResumeRejected.into());
+        }
+        if self.resume_from > 0 && err.is_range_error() {
+            // The server does not support byte ranges; so we start over from the beginning,
+            // discarding the bytes we've already written.
+            easy.get_ref().progress.suspend(|| {
+                eprintln!("The server for {url} does not support resuming; restarting the fetch.")
+            });
+            easy.get_ref().emit(&Event::Retry {
+                url,
+                error: "The server does not support resuming",
+                wait_seconds: 0.0,
+                retries_left: self.retries_left,
+                resume_from: 0,
+            });
+            self.resume_from = 0;
+            return Ok(Duration::ZERO);
+        }
+        let connect_code = easy.http_connectcode().unwrap_or_default();
+        if self.retries_left == 0 || !is_retryable(&err, response_code, connect_code) {
+            return Err(fetch_error(err, easy, &options.proxy))
+                .with_context(|| format!("Failed to fetch {url}"));
+        }
+        let wait = self.wait(easy.get_ref().headers.retry_after);
+        if let Some(max_time) = options.retry.max_time
+            && self.start.elapsed() + wait > max_time
+        {
+            return Err(fetch_error(err, easy, &options.proxy)).with_context(|| {
+                format!("Failed to fetch {url} within the maximum retry time of {max_time:.1?}")
+            });
+        }
+        easy.get_ref().progress.suspend(|| {
+            eprintln!(
+                "Transient problem fetching {url}: {err}. Will retry in {wait:.1?}. \
+                {retries_left} {retries} left.",
+                retries_left = self.retries_left,
+                retries = if self.retries_left == 1 {
+                    "retry"
+                } else {
+                    "retries"
+                }
+            )
+        });
+        self.retries_left -= 1;
+        self.delay = (self.delay * 2).min(MAX_RETRY_DELAY);
+        self.resume_from = if self.use_ranges {
+            easy.get_ref().written
+        } else {
+            0
+        };
+        easy.get_ref().emit(&Event::Retry {
+            url,
+            error: &err.to_string(),
+            wait_seconds: wait.as_secs_f64(),
+            retries_left: self.retries_left,
+            resume_from: self.resume_from,
+        });
+        Ok(wait)
+    }
+}
+
+/// Performs the configured fetch, retrying transient failures.
+fn perform<W: Write>(
+    easy: &mut Easy2<FetchHandler<W>>,
+    url: &str,
+    options: &Options,
+    resumed: bool,
+) -> Result<()> {
+    let mut attempts = Attempts::new(options, easy.get_ref().written, resumed);
+    loop {
+        attempts.begin(easy)?;
+        let err = match easy.perform() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let wait = attempts.failed(easy, err, url, options)?;
+        interrupt::sleep(wait);
+        if interrupt::interrupted() {
+            return Err(interrupted(url));
+        }
+    }
+}
+
+/// The error for a fetch interrupted while waiting to retry.
+fn interrupted(url: &str) -> anyhow::Error {
+    anyhow::Error::new(Failure::new(
+        Category::Interrupted,
+        "The fetch was interrupted",
+    ))
+    .context(format!("Failed to fetch {url}", url = redact_url(url)))
+}
+
+/// A fetch in flight as part of `fetch_all`.
+struct Transfer<'a, W: Write> {
+    url: &'a str,
+    attempts: Attempts,
+    // The fetch when it is waiting to start its next attempt.
+    easy: Option<Easy2<FetchHandler<W>>>,
+    ready_at: Instant,
+    handle: Option<Easy2Handle<FetchHandler<W>>>,
+    result: Option<Result<Response>>,
+}
+
+/// Fetches each of the given URLs into its paired output concurrently, running at most
+/// `parallel_max` fetches at once and multiplexing them over shared HTTP/2 connections where
+/// possible.
+///
+/// Returns the outcome of each fetch in the order given; the failure of one fetch does not affect
+/// the others.
+pub(crate) fn fetch_all<W: Write>(
+    requests: Vec<(&str, W)>,
+    options: &Options,
+    parallel_max: usize,
+) -> Result<Vec<Result<Response>>> {
+    let mut multi = Multi::new();
+    multi
+        .pipelining(false, true)
+        .context("Failed to enable HTTP/2 multiplexing")?;
+    let progress = MultiProgress::new();
+    let mut transfers = vec![];
+    for (url, output) in requests {
+        let (easy, result) = match configure(url, output, options, None, None, || {
+            progress.add(ProgressBar::no_length())
+        }) {
+            Ok(mut easy) => match easy.pipewait(true) {
+                Ok(()) => (Some(easy), None),
+                Err(err) => (
+                    None,
+                    Some(Err(err).context("Failed to configure multiplexing")),
+                ),
+            },
+            Err(err) => (None, Some(Err(err))),
+        };
+        transfers.push(Transfer {
+            url,
+            attempts: Attempts::new(options, 0, false),
+            easy,
+            ready_at: Instant::now(),
+            handle: None,
+            result,
+        });
+    }
+
+    loop {
+        // N.B.: Fetches in flight are aborted by their progress callback once interrupted; so we
+        // just need to fail those waiting to start.
+        if interrupt::interrupted() {
+            for transfer in &mut transfers {
+                if let Some(easy) = transfer.easy.take() {
+                    let result = Err(interrupted(transfer.url));
+                    easy.get_ref().finish(&result);
+                    transfer.result = Some(result);
+                }
+            }
+        }
+        let now = Instant::now();
+        let mut running = transfers.iter().filter(|t| t.handle.is_some()).count();
+        for (index, transfer) in transfers.iter_mut().enumerate() {
+            if running >= parallel_max {
+                break;
+            }
+            if transfer.ready_at > now {
+                continue;
+            }
+            let Some(mut easy) = transfer.easy.take() else {
+                continue;
+            };
+            let started = transfer.attempts.begin(&mut easy).and_then(|()| {
+                let mut handle = multi
+                    .add2(easy)
+                    .context("Failed to start a concurrent fetch")?;
+                handle
+                    .set_token(index)
+                    .context("Failed to start a concurrent fetch")?;
+                Ok(handle)
+            });
+            match started {
+                Ok(handle) => {
+                    transfer.handle = Some(handle);
+                    running += 1;
+                }
+                Err(err) => transfer.result = Some(Err(err)),
+            }
+        }
+        let next_ready_at = transfers
+            .iter()
+            .filter(|transfer| transfer.easy.is_some())
+            .map(|transfer| transfer.ready_at)
+            .min();
+        if running == 0 {
+            match next_ready_at {
+                Some(ready_at) => {
+                    interrupt::sleep(ready_at.saturating_duration_since(Instant::now()));
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        multi
+            .perform()
+            .context("Failed to perform concurrent fetches")?;
+        let mut completed = vec![];
+        multi.messages(|message| {
+            if let Ok(index) = message.token()
+                && let Some(result) = message.result()
+            {
+                completed.push((index, result));
+            }
+        });
+        for (index, result) in completed {
+            let transfer = &mut transfers[index];
+            let Some(handle) = transfer.handle.take() else {
+                continue;
+            };
+            let mut easy = multi
+                .remove2(handle)
+                .context("Failed to finish a concurrent fetch")?;
+            let result = match result {
+                Ok(()) => response(&mut easy, transfer.url),
+                Err(err) => match transfer
+                    .attempts
+                    .failed(&mut easy, err, transfer.url, options)
+                {
+                    Ok(wait) => {
+                        transfer.ready_at = Instant::now() + wait;
+                        transfer.easy = Some(easy);
+                        continue;
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            easy.get_ref().finish(&result);
+            transfer.result = Some(result);
+        }
+        let timeout = next_ready_at
+            .map(|ready_at| ready_at.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::from_secs(1))
+            .min(Duration::from_secs(1));
+        multi
+            .wait(&mut [], timeout)
+            .context("Failed to wait on concurrent fetches")?;
+    }
+    Ok(transfers
+        .into_iter()
+        .map(|transfer| {
+            transfer.result.unwrap_or_else(|| {
+                Err(anyhow!(
+                    "The fetch of {url} never completed",
+                    url = redact_url(transfer.url)
+                ))
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{Receiver, channel};
+    use std::time::{Duration, Instant};
+
+    use sha2::{Digest, Sha256};
+
+    use serde_json::json;
+
+    use super::{
+        Attempts, MAX_RETRY_DELAY, Options, ProgressInterval, ProgressLines, Proxy, Retry,
+        terse_duration,
+    };
+    use crate::config::Fingerprint;
+    use crate::error::Category;
+    use crate::events::tests::Recorder;
+
+    const URL: &str =
+        "https://github.com/a-scie/jump/releases/download/v0.2.1/scie-jump-linux-aarch64";
+
+    /// Serves each of the given raw HTTP responses to successive connections, returning the URL
+    /// to fetch from and a channel that receives the raw request headers of each connection.
+    pub(crate) fn serve(responses: Vec<Vec<u8>>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{addr}/file", addr = listener.local_addr().unwrap());
+        let (requests_tx, requests_rx) = channel();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                requests_tx.send(request).unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+        (url, requests_rx)
+    }
+
+    #[test]
+    fn fetch() {
+        let mut buffer: Vec<u8> = Vec::new();
+        super::fetch(
+            URL,
+            &mut buffer,
+            &Options {
+                show_progress: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(1205568, buffer.len());
+        assert_eq!(
+            "937683255e98caf10745a674d7063bd38e9cbeb523b9f8ef4dbe8807abc35382".to_string(),
+            hex::encode(Sha256::digest(buffer))
+        );
+    }
+
+    #[test]
+    fn fetch_retry_resume() {
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234".to_vec(),
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\n\
+            Content-Length: 5\r\n\r\n56789"
+                .to_vec(),
+        ]);
+        let mut buffer: Vec<u8> = Vec::new();
+        super::fetch(
+            &url,
+            &mut buffer,
+            &Options {
+                retry: Retry {
+                    count: 2,
+                    max_time: Some(Duration::from_secs(30)),
+                },
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(b"0123456789".as_slice(), buffer.as_slice());
+        assert!(!requests.recv().unwrap().contains("Range:"));
+        assert!(!requests.recv().unwrap().contains("Range:"));
+        assert!(requests.recv().unwrap().contains("Range: bytes=5-"));
+    }
+
+    #[test]
+    fn fetch_retry_restart() {
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789".to_vec(),
+        ]);
+        let mut buffer: Vec<u8> = Vec::new();
+        super::fetch(
+            &url,
+            &mut buffer,
+            &Options {
+                retry: Retry {
+                    count: 1,
+                    max_time: None,
+                },
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(b"0123456789".as_slice(), buffer.as_slice());
+        assert!(!requests.recv().unwrap().contains("Range:"));
+        assert!(requests.recv().unwrap().contains("Range: bytes=5-"));
+        assert!(!requests.recv().unwrap().contains("Range:"));
+    }
+
+    #[test]
+    fn fetch_limit_rate() {
+        let content = vec![b'x'; 32 * 1024];
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {length}\r\n\r\n",
+            length = content.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&content);
+        let (url, _requests) = serve(vec![response]);
+        let mut buffer: Vec<u8> = Vec::new();
+        let start = Instant::now();
+        super::fetch(
+            &url,
+            &mut buffer,
+            &Options {
+                show_progress: true,
+                limit_rate: Some(16 * 1024),
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(content, buffer);
+        // N.B.: Curl lets the first chunk through before throttling; so 32K at 16K/s must take
+        // at least the 1 second the second half is held to.
+        assert!(
+            start.elapsed() >= Duration::from_millis(900),
+            "{elapsed:?}",
+            elapsed = start.elapsed()
+        );
+    }
+
+    #[test]
+    fn fetch_compressed() {
+        let content = b"0123456789".repeat(10);
+        let encode = |mut encoder: Box<dyn Write>| {
+            encoder.write_all(&content).unwrap();
+        };
+        let mut zlib = Vec::new();
+        encode(Box::new(flate2::write::ZlibEncoder::new(
+            &mut zlib,
+            flate2::Compression::fast(),
+        )));
+        let mut raw_deflate = Vec::new();
+        encode(Box::new(flate2::write::DeflateEncoder::new(
+            &mut raw_deflate,
+            flate2::Compression::fast(),
+        )));
+        let mut brotli = Vec::new();
+        encode(Box::new(brotli::CompressorWriter::new(
+            &mut brotli,
+            4096,
+            5,
+            22,
+        )));
+        let zstd = zstd::encode_all(content.as_slice(), 0).unwrap();
+        let response = |encoding: &str, body: &[u8]| {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: {encoding}\r\n\
+                Content-Length: {length}\r\n\r\n",
+                length = body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(body);
+            response
+        };
+        let (url, requests) = serve(vec![
+            response("zstd", &zstd),
+            response("br", &brotli),
+            response("deflate", &zlib),
+            response("deflate", &raw_deflate),
+            response("compress", b"content"),
+        ]);
+        let options = Options {
+            compressed: true,
+            ..Default::default()
+        };
+        for _ in 0..4 {
+            let mut buffer: Vec<u8> = Vec::new();
+            super::fetch(&url, &mut buffer, &options, None).unwrap();
+            assert_eq!(content, buffer);
+        }
+        let request = requests.recv().unwrap();
+        assert!(
+            request.contains("Accept-Encoding: zstd, br, gzip, deflate"),
+            "{request}"
+        );
+
+        let err = super::fetch(&url, &mut Vec::new(), &options, None).unwrap_err();
+        assert_eq!(Category::Network, Category::of(&err));
+        assert!(
+            format!("{err:#}").contains("unsupported encoding compress"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn fetch_compressed_retry() {
+        // N.B.: This is "0123456789" repeated 10 times, gzipped.
+        let gzipped = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\x33\x30\x34\x32\x36\x31\x35\x33\
+        \xb7\xb0\x34\xa0\x19\x0b\x00\xbc\x4b\xfc\xb1\x64\x00\x00\x00";
+        let response = |body: &[u8]| {
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nAccept-Ranges: bytes\r\n\
+                Content-Length: {length}\r\n\r\n",
+                length = gzipped.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(body);
+            response
+        };
+        let (url, requests) = serve(vec![response(&gzipped[..20]), response(gzipped)]);
+        let mut buffer: Vec<u8> = Vec::new();
+        super::fetch(
+            &url,
+            &mut buffer,
+            &Options {
+                retry: Retry {
+                    count: 1,
+                    max_time: None,
+                },
+                compressed: true,
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(b"0123456789".repeat(10), buffer);
+        for _ in 0..2 {
+            let request = requests.recv().unwrap();
+            assert!(request.contains("Accept-Encoding: "), "{request}");
+            assert!(!request.contains("Range:"), "{request}");
+        }
+    }
+
+    #[test]
+    fn retry_wait() {
+        let attempts = Attempts::new(&Options::default(), 0, false);
+        assert_eq!(Duration::from_secs(1), attempts.wait(None));
+        assert_eq!(
+            Duration::from_secs(2),
+            attempts.wait(Some(Duration::from_secs(2)))
+        );
+        assert_eq!(
+            MAX_RETRY_DELAY,
+            attempts.wait(Some(Duration::from_secs(24 * 60 * 60)))
+        );
+    }
+
+    #[test]
+    fn fetch_all() {
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ncontent".to_vec(),
+        ]);
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut results = super::fetch_all(
+            vec![
+                (url.as_str(), &mut buffer),
+                ("file:///does/not/exist", &mut Vec::new()),
+            ],
+            &Options {
+                retry: Retry {
+                    count: 1,
+                    max_time: None,
+                },
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+        assert_eq!(2, results.len());
+        assert!(results.pop().unwrap().is_err());
+        assert!(results.pop().unwrap().is_ok());
+        assert_eq!(b"content".as_slice(), buffer.as_slice());
+        assert_eq!(2, requests.iter().count());
+    }
+
+    #[test]
+    fn fetch_events() {
+        let (url, _requests) = serve(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\r\n"
+                .to_vec(),
+            b"HTTP/1.1 302 Found\r\nLocation: /moved\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ncontent".to_vec(),
+        ]);
+        let recorder = Recorder::default();
+        let options = Options {
+            retry: Retry {
+                count: 1,
+                max_time: None,
+            },
+            events: Some(recorder.events()),
+            ..Default::default()
+        };
+        let mut buffer: Vec<u8> = Vec::new();
+        super::fetch(
+            &url,
+            &mut buffer,
+            &options,
+            Some(Fingerprint {
+                size: Some(7),
+                hash: None,
+            }),
+        )
+        .unwrap();
+        assert_eq!(b"content".as_slice(), buffer.as_slice());
+        super::fetch("file:///does/not/exist", Vec::new(), &options, None).unwrap_err();
+
+        let events = recorder
+            .recorded()
+            .into_iter()
+            .filter(|event| event["event"] != "progress")
+            .collect::<Vec<_>>();
+        let moved = url.replace("/file", "/moved");
+        assert_eq!(
+            vec![
+                json!({"event": "start", "url": url, "size": 7}),
+                json!({
+                    "event": "retry",
+                    "url": url,
+                    "error": events[1]["error"],
+                    "wait_seconds": 0.0,
+                    "retries_left": 0,
+                    "resume_from": 0
+                }),
+                json!({
+                    "event": "redirect", "url": url, "status": 302, "from": url, "to": moved
+                }),
+                json!({
+                    "event": "complete",
+                    "url": url,
+                    "effective_url": moved,
+                    "bytes": 7,
+                    "not_modified": false
+                }),
+                json!({"event": "start", "url": "file:///does/not/exist", "size": null}),
+                json!({
+                    "event": "error",
+                    "url": "file:///does/not/exist",
+                    "error": events[5]["error"],
+                    "exit_code": 1
+                }),
+            ],
+            events
+        );
+        assert!(
+            recorder
+                .recorded()
+                .iter()
+                .any(|event| event["event"] == "progress" && event["url"] == url.as_str())
+        );
+    }
+
+    #[test]
+    fn progress_lines() {
+        let mut lines = ProgressLines::new(ProgressInterval::Percent(25));
+        let due = |lines: &mut ProgressLines, bytes| lines.due(bytes, Some(200));
+        assert!(!due(&mut lines, 0));
+        assert!(!due(&mut lines, 49));
+        assert!(due(&mut lines, 50));
+        assert!(!due(&mut lines, 60));
+        assert!(due(&mut lines, 190));
+        assert!(due(&mut lines, 200));
+        assert!(!due(&mut lines, 200));
+        assert!(!lines.due(1000, None));
+
+        let mut lines = ProgressLines::new(ProgressInterval::Every(Duration::ZERO));
+        assert!(lines.due(0, None));
+        assert!(lines.due(0, Some(200)));
+        let mut lines = ProgressLines::new(ProgressInterval::Every(Duration::from_secs(60)));
+        assert!(!lines.due(100, Some(200)));
+
+        assert_eq!("18s", terse_duration(Duration::from_millis(18_900)));
+        assert_eq!("4m05s", terse_duration(Duration::from_secs(245)));
+        assert_eq!("1h02m", terse_duration(Duration::from_secs(3720)));
+    }
+
+    #[test]
+    fn fetch_proxy_connect_error() {
+        let (proxy_url, requests) = serve(vec![
+            b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n".to_vec(),
+        ]);
+        let proxy_url = proxy_url.trim_end_matches("/file").to_string();
+        let err = super::fetch(
+            "https://example.org/file",
+            Vec::new(),
+            &Options {
+                proxy: Proxy {
+                    url: Some(proxy_url.clone()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap_err();
+        assert!(
+            requests
+                .recv()
+                .unwrap()
+                .starts_with("CONNECT example.org:443 HTTP/1.1\r\n")
+        );
+        let chain = err.chain().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!("Failed to fetch https://example.org/file", chain[0]);
+        assert_eq!(
+            format!("The proxy CONNECT request to {proxy_url} failed with HTTP status 407"),
+            chain[1]
+        );
+    }
+}
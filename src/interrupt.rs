@@ -0,0 +1,75 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::error::Category;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The temporary files and directories of in-flight downloads that should be removed if we are
+/// forced to exit.
+static PENDING: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+/// Arranges for Ctrl-C (or SIGTERM) to stop in-flight fetches gracefully.
+///
+/// The first interrupt aborts any in-flight fetch so that it fails normally, which cleans up
+/// temporary files and records the progress of resumable downloads. A second interrupt exits
+/// immediately after removing any temporary files and directories.
+pub(crate) fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            if let Ok(pending) = PENDING.lock() {
+                for path in pending.iter() {
+                    let _ = std::fs::remove_file(path).or_else(|_| std::fs::remove_dir_all(path));
+                }
+            }
+            std::process::exit(Category::Interrupted.exit_code());
+        }
+    })
+    .context("Failed to install a Ctrl-C handler")
+}
+
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// How often a sleep checks whether it has been interrupted.
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+/// Sleeps for the given duration, cutting the sleep short if interrupted.
+pub(crate) fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !interrupted() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        std::thread::sleep(remaining.min(SLEEP_STEP));
+    }
+}
+
+/// Registers a temporary file or directory for removal on a forced exit for as long as it is alive.
+pub(crate) struct Pending(PathBuf);
+
+impl Pending {
+    pub(crate) fn register(path: &Path) -> Self {
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.push(path.to_path_buf());
+        }
+        Self(path.to_path_buf())
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = PENDING.lock() {
+            pending.retain(|path| path != &self.0);
+        }
+    }
+}
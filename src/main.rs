@@ -1,148 +1,331 @@
 // Copyright 2022 Science project contributors.
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::BTreeMap;
+mod cache;
+mod config;
+mod error;
+mod events;
+mod extract;
+mod fetch;
+mod interrupt;
+mod output;
+mod placeholders;
+mod redact;
+mod trace;
+mod write_out;
+
 use std::env;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Seek, Write};
+use std::num::NonZeroUsize;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result, anyhow};
-use curl::easy::{Easy2, Handler, List, NetRc, WriteError};
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use serde::Deserialize;
-use url::Url;
 
-#[derive(Deserialize)]
-struct Config {
-    ptex: BTreeMap<PathBuf, String>,
-}
+use crate::cache::{Cache, Tee, digest_path};
+use crate::config::{Config, Fingerprint};
+use crate::error::{Category, Failure};
+use crate::events::Events;
+use crate::extract::{ArchiveType, Extraction, Selection};
+use crate::fetch::{
+    DEFAULT_PROGRESS_INTERVAL, Options, ProgressInterval, Response, Resume, ResumeRejected, Retry,
+    Tls, fetch, fetch_all, fetch_resumable,
+};
+use crate::output::{AtomicFile, Mode, PartFile};
+use crate::redact::redact_url;
+use crate::trace::Trace;
+use crate::write_out::WriteOut;
 
-impl Config {
-    fn parse<R: Read>(reader: R) -> Result<Self> {
-        let config: Self =
-            serde_json::from_reader(reader).context("Failed to parse ptex config")?;
-        Ok(config)
-    }
-}
+// N.B.: This matches curl's own default for --parallel-max.
+const DEFAULT_PARALLEL_MAX: NonZeroUsize = NonZeroUsize::new(50).unwrap();
 
-struct FetchHandler<W: Write> {
+fn fetch_manifest<R: Read, W: Write>(
+    lift_manifest: R,
+    file_path: &Path,
     output: W,
-    progress: ProgressBar,
-    show_headers: bool,
-}
-
-#[cfg(target_family = "windows")]
-const NEWLINE: &str = "\r\n";
-
-#[cfg(target_family = "unix")]
-const NEWLINE: &str = "\n";
-
-fn write(state: &ProgressState, w: &mut dyn std::fmt::Write) {
-    write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+    options: &Options,
+    cache: Option<&Cache>,
+) -> Result<()> {
+    let config = Config::parse(lift_manifest)?;
+    source_file(&config, file_path, output, options, cache)
 }
 
-impl<W: Write> FetchHandler<W> {
-    fn new(url: &str, output: W, show_headers: bool, show_progress: bool) -> Self {
-        let progress = if show_progress {
-            let progress = ProgressBar::no_length();
-            progress.set_prefix(format!("Downloading {url}...{NEWLINE}"));
-            progress.set_style(
-                ProgressStyle::with_template(
-                    "{prefix}[{elapsed_precise}] [{bar:30}] {bytes}/{total_bytes} (eta: {eta})",
-                )
-                .expect("The template string is known-good.")
-                .with_key("eta", write)
-                .progress_chars("#>-"),
-            );
-            progress
-        } else {
-            ProgressBar::hidden()
-        };
-        Self {
-            output,
-            progress,
-            show_headers,
+/// Fetches the content of a lift manifest file as configured in the manifest's "ptex" object.
+fn source_file<W: Write>(
+    config: &Config,
+    file_path: &Path,
+    mut output: W,
+    options: &Options,
+    cache: Option<&Cache>,
+) -> Result<()> {
+    let entry = config.ptex.get(file_path).with_context(|| {
+        Failure::new(
+            Category::NoMapping,
+            format!(
+                "Did not find an URL mapping for file {path}.",
+                path = file_path.display()
+            ),
+        )
+    })?;
+    let invalid_config = || {
+        Failure::new(
+            Category::Config,
+            format!(
+                "Invalid ptex configuration for file {path}.",
+                path = file_path.display()
+            ),
+        )
+    };
+    let options = &entry.options(options).with_context(invalid_config)?;
+    let urls = entry.urls().with_context(invalid_config)?;
+    let fingerprint = config.fingerprint(file_path);
+    let fetch_urls = |output: &mut dyn Write| {
+        match urls.as_slice() {
+            [] => Err(anyhow::Error::new(Failure::new(
+                Category::Config,
+                "The URL mirror list is empty.",
+            ))),
+            [url] => fetch(url, output, options, fingerprint.clone()),
+            mirrors => fetch_mirrors(mirrors, output, options, fingerprint.clone()),
         }
+        .map(|_response| ())
+    };
+    let cache_key = fingerprint
+        .as_ref()
+        .and_then(|fingerprint| fingerprint.hash.as_deref());
+    match (cache, cache_key) {
+        (Some(cache), Some(hash)) => fetch_cached(
+            cache,
+            hash,
+            fingerprint
+                .as_ref()
+                .and_then(|fingerprint| fingerprint.size),
+            output,
+            fetch_urls,
+        ),
+        _ => fetch_urls(&mut output),
     }
+    .with_context(|| format!("Failed to source file {file}", file = file_path.display()))
 }
 
-impl<W: Write> Handler for FetchHandler<W> {
-    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
-        self.output
-            .write_all(data)
-            .expect("Failed to write data to output");
-        Ok(data.len())
-    }
-
-    fn header(&mut self, data: &[u8]) -> bool {
-        if self.show_headers
-            && let Ok(header) = std::str::from_utf8(data)
-        {
-            eprint!("{header}");
+fn fetch_cached<W: Write>(
+    cache: &Cache,
+    hash: &str,
+    size: Option<u64>,
+    mut output: W,
+    fetch_urls: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    match cache.get(hash, size) {
+        Ok(Some(mut file)) => {
+            std::io::copy(&mut file, &mut output).context("Failed to write cached content")?;
+            return Ok(());
         }
-        true
+        Ok(None) => {}
+        Err(err) => eprintln!("Not using the ptex cache: {err:#}"),
     }
-
-    fn progress(&mut self, dltotal: f64, dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
-        if dltotal > 0.0 {
-            self.progress.set_length(dltotal as u64)
+    let mut writer = match cache.writer(hash) {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("Not using the ptex cache: {err:#}");
+            return fetch_urls(&mut output);
         }
-        self.progress.set_position(dlnow as u64);
-        true
+    };
+    fetch_urls(&mut Tee(&mut output, &mut writer))?;
+    if let Err(err) = writer.commit() {
+        eprintln!("Failed to add fetched content to the ptex cache: {err:#}");
     }
+    Ok(())
 }
 
-fn fetch_manifest<R: Read, W: Write>(
+/// What became of a sourced lift manifest file when prefetching.
+enum Prefetched {
+    Fetched,
+    Skipped(&'static str),
+    Failed(anyhow::Error),
+}
+
+/// Fetches every file in the lift manifest that ptex can source into `dest`, fetching up to
+/// `parallel_max` files at once.
+fn prefetch<R: Read>(
     lift_manifest: R,
-    file_path: &Path,
-    output: W,
-    headers: Vec<String>,
-    show_headers: bool,
-    show_progress: bool,
-) -> Result<()> {
+    dest: &Path,
+    options: &Options,
+    cache: Option<&Cache>,
+    parallel_max: NonZeroUsize,
+) -> Result<Vec<(PathBuf, Prefetched)>> {
     let config = Config::parse(lift_manifest)?;
-    let url = config.ptex.get(file_path).with_context(|| {
-        format!(
-            "Did not find an URL mapping for file {path}.",
-            path = file_path.display()
+    let files = config
+        .sourced_files()
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(files.iter().map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|scope| {
+        for _ in 0..parallel_max.get().min(files.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(file_path) = files.get(index) else {
+                        break;
+                    };
+                    let outcome = prefetch_file(&config, file_path, dest, options, cache);
+                    if let Ok(mut outcomes) = outcomes.lock() {
+                        outcomes[index] = Some(outcome);
+                    }
+                }
+            });
+        }
+    });
+    let outcomes = outcomes
+        .into_inner()
+        .map_err(|_| anyhow!("A prefetch thread panicked"))?;
+    Ok(files
+        .into_iter()
+        .zip(outcomes)
+        .map(|(file_path, outcome)| {
+            let outcome = outcome.unwrap_or_else(|| {
+                Prefetched::Failed(anyhow!(
+                    "The prefetch of {} never completed",
+                    file_path.display()
+                ))
+            });
+            (file_path, outcome)
+        })
+        .collect())
+}
+
+fn prefetch_file(
+    config: &Config,
+    file_path: &Path,
+    dest: &Path,
+    options: &Options,
+    cache: Option<&Cache>,
+) -> Prefetched {
+    if !config.ptex.contains_key(file_path) {
+        return Prefetched::Skipped("ptex has no URL mapping for it");
+    }
+    // N.B.: File names come from the lift manifest; so we ensure they cannot escape `dest`.
+    if !file_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Prefetched::Failed(anyhow::Error::new(Failure::new(
+            Category::Config,
+            format!(
+                "Refusing to prefetch {path} since it is not a relative path within the \
+                destination directory.",
+                path = file_path.display()
+            ),
+        )));
+    }
+    let path = dest.join(file_path);
+    if is_prefetched(&path, config.fingerprint(file_path)) {
+        return Prefetched::Skipped("it is already present");
+    }
+    let result = output_dir(
+        &Destination::Path(path.clone()),
+        &SaveOptions {
+            create_dirs: true,
+            ..Default::default()
+        },
+    )
+    .and_then(|dir| {
+        create_file(
+            &format!("the lift manifest file {}", file_path.display()),
+            dir,
         )
-    })?;
-    fetch(url, output, headers, show_headers, show_progress)
-        .with_context(|| format!("Failed to source file {file}", file = file_path.display()))
+    })
+    .and_then(|mut file| {
+        source_file(config, file_path, &mut file, options, cache)?;
+        file.commit(&path).context(Failure::new(
+            Category::Write,
+            "Failed to save the fetched content",
+        ))
+    });
+    match result {
+        Ok(()) => Prefetched::Fetched,
+        Err(err) => Prefetched::Failed(err),
+    }
 }
 
-fn fetch<W: Write>(
-    url: &str,
-    output: W,
-    headers: Vec<String>,
-    show_headers: bool,
-    show_progress: bool,
-) -> Result<()> {
-    let mut easy = Easy2::new(FetchHandler::new(url, output, show_headers, show_progress));
-    easy.follow_location(true)
-        .context("Failed to configure re-direct following")?;
-    easy.fail_on_error(true)
-        .context("Failed to configure fail on error behavior")?;
-    easy.netrc(NetRc::Optional)
-        .context("Failed to enable ~/.netrc parsing")?;
-    easy.url(url)
-        .with_context(|| format!("Failed to configure URL to fetch from as {url}"))?;
-    easy.progress(true)
-        .context("Failed to enable progress meter")?;
-    easy.useragent(format!("ptex/{version}", version = env!("CARGO_PKG_VERSION")).as_str())
-        .context("Failed to set User-Agent")?;
-    if !headers.is_empty() {
-        let mut curl_headers = List::new();
-        for header in headers {
-            curl_headers
-                .append(header.as_str())
-                .with_context(|| format!("Failed to set custom header {header}"))?;
+/// Returns true if the file at `path` is known to match the expected fingerprint.
+///
+/// A file whose fingerprint is unknown cannot be checked; so it is never considered present.
+fn is_prefetched(path: &Path, fingerprint: Option<Fingerprint>) -> bool {
+    let Some(Fingerprint { size, hash }) = fingerprint else {
+        return false;
+    };
+    let Ok(metadata) = path.metadata() else {
+        return false;
+    };
+    if let Some(size) = size
+        && metadata.len() != size
+    {
+        return false;
+    }
+    let Some(hash) = hash else {
+        return size.is_some();
+    };
+    digest_path(path).is_ok_and(|digest| hash.eq_ignore_ascii_case(&digest))
+}
+
+fn fetch_mirrors<W: Write>(
+    urls: &[String],
+    mut output: W,
+    options: &Options,
+    fingerprint: Option<Fingerprint>,
+) -> Result<Response> {
+    // N.B.: An attempt with a later mirror to fall back to is spooled to a temporary file and only
+    // copied to the output once it has been fully fetched and verified; so a failed attempt never
+    // leaves partial content behind for the next. The last attempt has nothing to protect the
+    // output for; so it fetches straight to it.
+    let mut category = Category::Other;
+    for (index, url) in urls.iter().enumerate() {
+        let result = if index + 1 == urls.len() {
+            fetch(url, &mut output, options, fingerprint.clone())
+        } else {
+            let mut spool =
+                tempfile::tempfile().context("Failed to create a temporary spool file")?;
+            let result = fetch(url, &mut spool, options, fingerprint.clone());
+            if result.is_ok() {
+                spool
+                    .rewind()
+                    .context("Failed to rewind the temporary spool file")?;
+                std::io::copy(&mut spool, &mut output).with_context(|| {
+                    format!(
+                        "Failed to write content fetched from {url}",
+                        url = redact_url(url)
+                    )
+                })?;
+            }
+            result
+        };
+        match result {
+            Ok(response) => return Ok(response),
+            Err(err) => {
+                eprintln!("{err:#}");
+                category = Category::of(&err);
+            }
         }
-        easy.http_headers(curl_headers)
-            .context("Failed to configure custom headers")?;
     }
-    easy.perform()
-        .with_context(|| format!("Failed to fetch {url}"))
+    // N.B.: We categorize the failure by the last attempt's failure.
+    Err(anyhow::Error::new(Failure::new(
+        category,
+        format!(
+            "Failed to fetch from all {count} URLs tried:\n{urls}",
+            count = urls.len(),
+            urls = urls
+                .iter()
+                .map(|url| format!("  {url}", url = redact_url(url)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    )))
 }
 
 fn usage(exit_code: i32, program_name: Option<String>) -> ! {
@@ -152,10 +335,26 @@ fn usage(exit_code: i32, program_name: Option<String>) -> ! {
     {bin_name} -h|--help
     {bin_name}:
         [-H|--header]* (-D|--dump-header) (-s|--silent)
+        (--cache-dir <dir>) [fetch options]
         [lift manifest path] [file name]
     {bin_name}:
-        (-O|--remote-name) [-H|--header]* (-D|--dump-header)
-        (-s|--silent) [URL]
+        (-O|--remote-name (-J|--remote-header-name)|[-o|--output <path>]*)
+        (--output-dir <dir>) (--url-file <path|->) (--parallel-max <n>)
+        (--create-dirs) (-C|--continue-at -) (-R|--remote-time)
+        (-x|--executable|--chmod <mode>)
+        (--extract <dir> (--archive-type <type>)
+        (--strip-components <n>) [--include <pattern>]*)
+        (-z|--time-cond <date|file>)
+        (--etag-save <file>) (--etag-compare <file>)
+        (-w|--write-out <format>) [-H|--header]* (-D|--dump-header)
+        (-s|--silent) [fetch options] [URL]*
+    {bin_name} prefetch:
+        --dest <dir> (--parallel-max <n>) (--cache-dir <dir>)
+        [-H|--header]* (-D|--dump-header) [fetch options]
+        [lift manifest path]
+    {bin_name} cache:
+        (--cache-dir <dir>) list|verify|prune (--max-age <age>)
+        (--max-size <size>)
 
     The `ptex` binary is a statically compiled URL fetcher based on
     libcurl. It supports the HTTP protocol up through HTTP/2, the FTP
@@ -174,11 +373,18 @@ fn usage(exit_code: i32, program_name: Option<String>) -> ! {
 
 {bin_name}:
     [-H|--header]*     Pass custom header(s) to server.
-    (-D|--dump-header) Dump the headers received to stderr. Can also be
-                       set via non-empty PTEX_DUMP_HEADERS env var.
-    (-s|--silent)      Turn off printing of fetch progress. By default
-                       progress is printed to stderr only if a terminal
-                       is detected.
+    (-D|--dump-header) Dump the headers received to stderr, with any
+                       credentials redacted. Can also be set via
+                       non-empty PTEX_DUMP_HEADERS env var.
+    (-s|--silent)      Turn off printing of fetch progress to stderr.
+                       See --progress for how it is printed otherwise.
+    (--cache-dir <dir>)
+                       Cache fetched files that have a "hash" in the
+                       lift manifest in this directory, keyed by their
+                       hash, and serve later fetches of the same hash
+                       from it after checking it still matches. Corrupt
+                       entries are re-fetched. Can also be set via the
+                       PTEX_CACHE_DIR env var.
     [lift manifest path] [file name]
 
     For use in a scie file source binding. The first argument is the
@@ -226,24 +432,383 @@ fn usage(exit_code: i32, program_name: Option<String>) -> ! {
     The file name is passed in as a second argument to the source
     binding by the `scie-jump` and `ptex` uses that file name to look up
     the URL to fetch the file from in the top-level "ptex" URL database
-    object.
+    object. If the matching lift manifest file entry has a "size" or
+    "hash", the fetched content is checked against them as it streams
+    and `ptex` exits with a non-zero status if either does not match.
+
+    A file's URL can also be given as an array of mirror URLs. These
+    are tried in order, moving on to the next mirror when a fetch fails
+    for any reason, including a size or hash mismatch:
+
+      "ptex": {{
+        "some-file-to-be-fetched.tar.gz": [
+          "https://example.org/downloads/some-file-to-be-fetched.tar.gz",
+          "https://mirror.example.org/some-file-to-be-fetched.tar.gz"
+        ]
+      }}
+
+    A file's entry can also be an object carrying its URL (or array of
+    mirror URLs) along with options for fetching it:
+
+      "ptex": {{
+        "some-file-to-be-fetched.tar.gz": {{
+          "url": "https://example.org/downloads/some-file-to-be-fetched.tar.gz",
+          "headers": {{
+            "Accept": "application/octet-stream"
+          }},
+          "netrc": "required",
+          "timeout": 300,
+          "compressed": true
+        }}
+      }}
+
+    The "headers" are sent in addition to any passed via -H, replacing
+    any of the same name. The "netrc" use of credentials from ~/.netrc
+    can be "ignored", "optional" (the default) or "required". The
+    "timeout" is the maximum number of seconds the fetch may take,
+    overriding any --max-time. The "compressed" boolean overrides
+    --compressed. A "proxy" object can also be supplied with any of
+    "url", "user", "noproxy" and "cacert" keys; these override the
+    corresponding --proxy, --proxy-user, --noproxy and --proxy-cacert
+    fetch options.
+
+    URLs and header values in the "ptex" object can refer to
+    environment variables using the same placeholder syntax as the rest
+    of the lift manifest: `{{scie.env.NAME}}` expands to the value of
+    the NAME env var and it is an error for it to be unset, whereas
+    `{{scie.env.NAME=default}}` expands to "default" when NAME is unset.
+    For example:
+
+      "ptex": {{
+        "some-file-to-be-fetched.tar.gz": {{
+          "url": "https://{{scie.env.MIRROR=example.org}}/some-file-to-be-fetched.tar.gz",
+          "headers": {{
+            "Authorization": "Bearer {{scie.env.TOKEN}}"
+          }}
+        }}
+      }}
 
     See more documentation on scie packaging configuration here:
      https://github.com/a-scie/jump/blob/main/docs/packaging.md
 
 {bin_name}:
     (-O|--remote-name) Write output to a file named as the remote file.
+                       The name is the percent-decoded last path
+                       segment of the URL fetched from after following
+                       redirects.
+    (-J|--remote-header-name)
+                       With -O, prefer the file name the server
+                       suggests in a Content-Disposition header. File
+                       names containing path separators or `..` are
+                       rejected.
+    (-o|--output <path>)
+                       Write output to this file instead of stdout. A
+                       path of `-` means stdout. When fetching several
+                       URLs, give one -o per URL, in order.
+    (--output-dir <dir>)
+                       Resolve relative -o paths and -O file names
+                       against this directory instead of the current
+                       directory.
+    (--url-file <path|->)
+                       Also fetch the URLs listed in this file, or on
+                       stdin for `-`; one per line, each optionally
+                       followed by whitespace and the path to save it
+                       to. URLs without a path are saved under their
+                       remote file name as with -O. Blank lines and
+                       lines starting with `#` are ignored.
+    (--parallel-max <n>)
+                       The maximum number of URLs to fetch at once when
+                       fetching several. Defaults to 50.
+    (--create-dirs)    Create any missing parent directories of the
+                       output file, including --output-dir.
+    (-C|--continue-at -)
+                       Keep the partial content of a failed or
+                       interrupted fetch as <file>.ptex-part, along
+                       with a <file>.ptex-part.json record of its
+                       progress, and resume from there on the next run.
+                       If the remote file changed in the meantime or
+                       the server does not support resuming, the fetch
+                       starts over.
+    (-x|--executable)  Make the output file executable by everyone who
+                       can read it.
+    (--chmod <mode>)   Set the output file's permissions to this octal
+                       mode, e.g. 755. Takes precedence over -x.
+    (-R|--remote-time) Set the output file's modification time from
+                       the server's Last-Modified header.
+    (--extract <dir>)  Extract the fetched archive into this directory
+                       instead of saving it. The directory must not
+                       exist yet or be empty. Cannot be combined with
+                       -O, -o or -C.
+    (--archive-type <type>)
+                       The type of the archive to --extract; one of
+                       zip, tar, tar.bz2, tbz2, tar.gz, tgz, tar.xz,
+                       tar.lzma, tlz, tar.zst or tzst as for the type
+                       of a scie lift manifest file. Defaults to the
+                       type implied by the extension of the URL's file
+                       name.
+    (--strip-components <n>)
+                       Strip this many leading path components from
+                       archive entries when extracting; entries with
+                       no components left are skipped.
+    [--include <pattern>]*
+                       Only extract the archive entries whose stripped
+                       paths match one of these patterns, where `*`
+                       and `?` match within a path component. Matching
+                       a directory includes everything under it.
+    (-z|--time-cond <date|file>)
+                       Only fetch if the remote file was modified after
+                       this HTTP date or the modification time of this
+                       file. A file that does not exist yet imposes no
+                       condition.
+    (--etag-save <file>)
+                       Save the server's ETag for the fetched content
+                       to this file.
+    (--etag-compare <file>)
+                       Only fetch if the server's ETag differs from the
+                       one saved in this file by --etag-save.
+    (-w|--write-out <format>)
+                       Print a report after each successful fetch, as
+                       described below. Use @<file> to read the format
+                       from a file, or @- from stdin.
     [-H|--header]*     Pass custom header(s) to server.
-    (-D|--dump-header) Dump the headers received to stderr. Can also be
-                       set via non-empty PTEX_DUMP_HEADERS env var.
-    (-s|--silent)      Turn off printing of fetch progress. By default
-                       progress is printed to stderr only if a terminal
-                       is detected.
+    (-D|--dump-header) Dump the headers received to stderr, with any
+                       credentials redacted. Can also be set via
+                       non-empty PTEX_DUMP_HEADERS env var.
+    (-s|--silent)      Turn off printing of fetch progress to stderr.
+                       See --progress for how it is printed otherwise.
     [URL]
 
     For use as a fully self-contained curl-like binary. The given URL is
-    fetched and the response is streamed to a file if -O or
-    --remote-name was specified and otherwise to stdout.
+    fetched and the response is streamed to a file if -O or -o was
+    specified and otherwise to stdout. Files are written to a temporary
+    file alongside and only renamed into place, with any requested mode
+    and modification time applied, once the fetch succeeds; so an
+    existing file is never replaced by a partial download. File modes
+    are ignored with a warning on Windows.
+
+    With --extract, the archive is decompressed and unpacked as it
+    streams in, without being saved, into a temporary directory
+    alongside that is only renamed into place once extraction
+    succeeds. Entries with absolute paths or `..` components and
+    symlinks pointing outside the directory are refused. Zip archives
+    must be streamable: entries whose sizes are only recorded after
+    their content are not supported.
+
+    Several URLs can be fetched at once by passing them along with -O
+    or one -o each, or via --url-file. They are fetched concurrently,
+    sharing connections and multiplexing over HTTP/2 where possible,
+    and each file is saved as described above. A failed fetch is
+    reported without affecting the others; ptex then exits with the
+    status of the first failure. -C, --extract, --etag-save and
+    --etag-compare only apply when fetching a single URL.
+
+    If the server responds to a -z or --etag-compare condition with 304
+    Not Modified, nothing is fetched, any existing output file is left
+    untouched and ptex exits successfully.
+
+    The -w format is printed to stdout with curl-style `%{{variable}}`
+    substitutions. The variables are: url, url_effective, http_code
+    (or response_code), num_redirects, content_type, remote_ip,
+    remote_port, size_download (bytes received), speed_download
+    (average bytes per second) and the time_namelookup, time_connect,
+    time_appconnect (TLS), time_pretransfer, time_starttransfer (time
+    to first byte) and time_total timings, in seconds, of the final
+    attempt. `%{{json}}` prints all of them as a JSON object and
+    `%{{stderr}}` and `%{{stdout}}` switch where the rest of the report
+    goes. `%%` is a literal `%` and `\n`, `\r` and `\t` are escapes;
+    e.g.: -w '%{{json}}\n'.
+
+{bin_name} prefetch:
+    --dest <dir>       The directory to fetch files into.
+    (--parallel-max <n>)
+                       The maximum number of files to fetch at once.
+                       Defaults to 50.
+    (--cache-dir <dir>)
+                       Use this cache as for sourcing a single file.
+                       Can also be set via the PTEX_CACHE_DIR env var.
+    [lift manifest path]
+
+    Fetches, up front, every file in the lift manifest whose "source"
+    binding runs ptex and that has a ptex URL mapping, rather than
+    waiting for the scie to source each on first use. A binding runs
+    ptex when its "exe" names a lift file, like "{{ptex}}", that is a
+    ptex binary going by its name or key; e.g.: ptex-linux-x86_64. Each
+    file is saved in --dest under its lift manifest name, which is the
+    flat layout sideloading tools and scies placed alongside the
+    directory expect, after checking any size and hash recorded for it
+    in the lift manifest. Files already present in --dest with the
+    recorded size and hash are skipped. A summary of the files fetched,
+    skipped and failed is printed and ptex exits with the status of the
+    first failure, if any.
+
+{bin_name} cache:
+    (--cache-dir <dir>) The cache directory to operate on. Defaults to
+                       the value of the PTEX_CACHE_DIR env var.
+    list               List cache entries with their size in bytes and
+                       the time since they were last used.
+    verify             Re-hash all cache entries and remove any that
+                       are corrupt.
+    prune              Remove cache entries last used longer ago than
+                       --max-age and then the least recently used
+                       entries until the cache is no larger than
+                       --max-size. At least one is required.
+    (--max-age <age>)  An age in seconds or with an s, m, h or d suffix;
+                       e.g.: 30d.
+    (--max-size <size>)
+                       A size in bytes or with a k, M, G or T suffix;
+                       e.g.: 10G.
+
+    Maintains the cache used by the --cache-dir option.
+
+Fetch options:
+
+    These options apply to fetches in either mode above.
+
+    (--retry <count>)  Retry a fetch that fails with a transient error
+                       (a timeout, a dropped connection or an HTTP 408,
+                       429, 500, 502, 503 or 504 response) up to this
+                       many times. Retries back off exponentially from
+                       1 second to at most 10 minutes, honoring any
+                       Retry-After header up to that same limit, and
+                       resume from the bytes already fetched when the
+                       server supports byte ranges. Defaults to 0. Can
+                       also be set via the PTEX_RETRY env var.
+    (--retry-max-time <seconds>)
+                       Do not start a retry once this many seconds have
+                       passed since the fetch began. Defaults to no
+                       limit. Can also be set via the
+                       PTEX_RETRY_MAX_TIME env var.
+    (--progress <bar|lines|json>)
+                       How to report fetch progress. By default a bar is
+                       drawn on stderr when it is a terminal and lines
+                       are printed otherwise; e.g.: in CI logs. With
+                       json, events are written to stderr as described
+                       below. Also --progress=<bar|lines|json>.
+    (--progress-interval <seconds|percent%>)
+                       How often to print a line of progress; either
+                       every so many seconds or every so many percent
+                       of the download, e.g.: 30 or 5%. Defaults to 10
+                       seconds. Can also be set via the
+                       PTEX_PROGRESS_INTERVAL env var.
+    (--compressed)     Ask the server for a zstd, brotli, gzip or
+                       deflate compressed response and decode it on the
+                       fly. Progress reports the bytes received along
+                       with the decoded bytes written. Ignored when
+                       resuming a partial download with -C.
+    (--proxy <url>)    Use the proxy at this URL; e.g.:
+                       http://proxy.example.org:3128. By default the
+                       standard http_proxy, https_proxy, all_proxy and
+                       no_proxy env vars are respected.
+    (--proxy-user <user:password>)
+                       Authenticate to the proxy with these credentials
+                       using Basic, Digest or NTLM authentication.
+    (--noproxy <hosts>)
+                       A comma-separated list of hosts to connect to
+                       directly, bypassing the proxy; `*` matches all
+                       hosts. Overrides the NO_PROXY env var.
+    (--proxy-cacert <file>)
+                       Verify the proxy's TLS certificate using the CA
+                       certificates in this PEM file.
+    (--connect-timeout <seconds>)
+                       Abort a fetch attempt if connecting to the server
+                       takes longer than this. Can also be set via the
+                       PTEX_CONNECT_TIMEOUT env var.
+    (--max-time <seconds>)
+                       Abort a fetch attempt if it takes longer than
+                       this in total. Can also be set via the
+                       PTEX_MAX_TIME env var.
+    (--speed-limit <bytes per second>)
+                       Abort a fetch attempt if it transfers slower than
+                       this for --speed-time seconds (30 by default).
+                       Accepts a k, M or G suffix. Can also be set via
+                       the PTEX_SPEED_LIMIT env var.
+    (--speed-time <seconds>)
+                       Abort a fetch attempt if it transfers slower than
+                       --speed-limit (1 byte per second by default) for
+                       this long. Can also be set via the
+                       PTEX_SPEED_TIME env var.
+    (--limit-rate <bytes per second>)
+                       Throttle fetches to at most this download rate.
+                       Accepts a k, M or G suffix. Progress shows the
+                       current rate against the limit. Can also be set
+                       via the PTEX_LIMIT_RATE env var.
+    (-v|--verbose)     Log each fetch's connections, TLS handshake,
+                       redirects and request and response headers to
+                       stderr.
+    (--trace <file>)   Log what --verbose does, with timestamps and the
+                       sizes of data transferred, to this file; or to
+                       stderr if `-`.
+    (--cacert <file>)  Verify servers' TLS certificates using the CA
+                       certificates in this PEM file. Can also be set
+                       via the PTEX_CA_BUNDLE or SSL_CERT_FILE env vars.
+    (--capath <dir>)   Verify servers' TLS certificates using the CA
+                       certificates in this OpenSSL hashed certificate
+                       directory. Can also be set via the PTEX_CA_PATH
+                       or SSL_CERT_DIR env vars.
+    (--cert <file>)    Present this PEM client certificate for mutual
+                       TLS. Can also be set via the PTEX_CLIENT_CERT env
+                       var.
+    (--key <file>)     The PEM private key for --cert. Can also be set
+                       via the PTEX_CLIENT_KEY env var.
+    (--pinnedpubkey <hashes>)
+                       Only accept servers whose public key matches;
+                       either a PEM or DER public key file or a
+                       `;`-separated list of `sha256//<base64 hash>`
+                       entries. Can also be set via the
+                       PTEX_PINNED_PUBKEY env var.
+
+    If this ptex binary was built with an embedded CA bundle, it is
+    used when neither --cacert nor --capath are configured. Otherwise
+    the OpenSSL default CA locations are used.
+
+    With --progress=json, each fetch reports newline-delimited JSON
+    objects with an "event" of "start", "progress" (at most 4 times a
+    second), "redirect", "retry" and finally "complete" or "error", all
+    carrying the "url" requested. Setting the PTEX_PROGRESS_FD env var
+    to an open file descriptor sends the events there instead of stderr
+    and implies --progress=json unless --progress=bar is given; this is
+    only supported on Unix.
+
+    Credentials are never logged by --verbose, --trace or -D: the values
+    of Authorization, Proxy-Authorization, Cookie and Set-Cookie headers
+    and FTP passwords, including those from ~/.netrc, are replaced with
+    REDACTED, as are the usernames and passwords in URLs and the values
+    of query parameters that look like credentials; e.g.: token or
+    X-Amz-Signature. URLs are redacted the same way everywhere else they
+    are shown: in progress output, events, -w variables and messages.
+
+Exit codes:
+    Where curl has an exit code for the same class of failure, ptex
+    uses it. Failures marked with a `*` are typically transient and
+    worth retrying.
+
+    0                  Success.
+    1                  An otherwise unclassified failure.
+    2                  Invalid command line usage.
+    3                  A malformed URL or one with no file name for -O.
+    5  *               Failed to resolve the proxy host.
+    6  *               Failed to resolve the server host.
+    7  *               Failed to connect to the server or proxy.
+    22                 The server responded with an HTTP error status.
+    23                 Failed to write the fetched content.
+    28 *               The fetch timed out.
+    35                 The TLS handshake failed.
+    56 *               The connection failed mid-transfer.
+    60                 The server's TLS certificate or public key
+                       failed verification.
+    97                 The proxy refused or failed the request.
+    100                The lift manifest is missing, unparseable or has
+                       an invalid ptex configuration for the file.
+    101                The lift manifest has no URL mapping for the
+                       file.
+    102                The fetched content did not match the size or
+                       hash recorded in the lift manifest.
+    103 *              The server responded with a transient HTTP error
+                       status (408, 429, 500, 502, 503 or 504).
+    104                The fetched archive could not be extracted; it
+                       is corrupt, not of the expected type or has
+                       unsafe entries.
+    130                The fetch was interrupted by Ctrl-C or SIGTERM.
 "#,
         bin_name = program_name.unwrap_or_else(|| env!("CARGO_BIN_NAME").to_string())
     );
@@ -260,38 +825,596 @@ impl<T> OrExit<T> for Result<T> {
             Ok(item) => item,
             Err(err) => {
                 eprintln!("{err:#}");
-                std::process::exit(1)
+                std::process::exit(Category::of(&err).exit_code())
             }
         }
     }
 }
 
-fn open_remote_filename(url: &str) -> Result<impl Write> {
-    let parsed_url = Url::parse(url)?;
-    let remote_path = PathBuf::from(parsed_url.path());
-    let remote_file_name = remote_path
-        .file_name()
-        .ok_or_else(|| anyhow!("Could not determine the remote file name of {url}"))?;
-    let local_path = env::current_dir()?.join(remote_file_name);
-    std::fs::File::create(&local_path).with_context(|| {
-        format!(
-            "Failed to open {local_path} for streaming {url} to.",
-            local_path = local_path.display()
+/// Where the content fetched from a URL is saved.
+enum Destination {
+    /// An explicit file path.
+    Path(PathBuf),
+    /// A file in the given directory named after the remote file.
+    RemoteName {
+        dir: PathBuf,
+        use_content_disposition: bool,
+    },
+}
+
+/// How a file fetched from a URL is saved.
+#[derive(Default)]
+struct SaveOptions {
+    /// Create any missing directories leading to the file.
+    create_dirs: bool,
+    /// Set the file's modification time from the server's Last-Modified header.
+    remote_time: bool,
+    /// Keep partial downloads so that a later run can resume them.
+    resume: bool,
+    mode: Option<Mode>,
+}
+
+impl SaveOptions {
+    /// Applies the requested metadata to a fetched file before it is moved into place.
+    fn apply(&self, file: &File, response: &Response) -> Result<()> {
+        if self.remote_time
+            && let Some(last_modified) = response.last_modified
+        {
+            file.set_modified(last_modified).context(Failure::new(
+                Category::Write,
+                "Failed to apply --remote-time",
+            ))?;
+        }
+        if let Some(mode) = self.mode {
+            mode.apply(file).context(Failure::new(
+                Category::Write,
+                "Failed to apply the file mode",
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the directory a destination file will be saved in, creating it if requested.
+fn output_dir<'a>(destination: &'a Destination, save_options: &SaveOptions) -> Result<&'a Path> {
+    let dir = match destination {
+        Destination::Path(path) => output::parent_dir(path),
+        Destination::RemoteName { dir, .. } => dir.as_path(),
+    };
+    if save_options.create_dirs {
+        std::fs::create_dir_all(dir).with_context(|| {
+            Failure::new(
+                Category::Write,
+                format!("Failed to create directory {dir}", dir = dir.display()),
+            )
+        })?;
+    }
+    Ok(dir)
+}
+
+/// Creates a file in `dir` to stream the fetched content described by `content` to.
+fn create_file(content: &str, dir: &Path) -> Result<AtomicFile> {
+    AtomicFile::create_in(dir).with_context(|| {
+        Failure::new(
+            Category::Write,
+            format!(
+                "Failed to open a file in {dir} for streaming {content} to.",
+                dir = dir.display()
+            ),
         )
     })
 }
 
+/// Moves a successfully fetched file into place at its destination.
+fn save(
+    url: &str,
+    file: AtomicFile,
+    destination: &Destination,
+    save_options: &SaveOptions,
+    response: &Response,
+) -> Result<()> {
+    if response.not_modified {
+        // N.B.: Any existing file is left as-is and the empty temporary file is discarded.
+        return Ok(());
+    }
+    let path = destination_path(url, destination, response)?;
+    save_options.apply(file.as_file(), response)?;
+    file.commit(&path).context(Failure::new(
+        Category::Write,
+        "Failed to save the fetched content",
+    ))
+}
+
+fn fetch_to_file(
+    url: &str,
+    destination: &Destination,
+    save_options: &SaveOptions,
+    options: &Options,
+) -> Result<Response> {
+    let dir = output_dir(destination, save_options)?;
+    if save_options.resume {
+        // N.B.: The partial download of a remote name is keyed by the name in the requested URL
+        // since the final name is not known until the fetch completes.
+        let part_path = match destination {
+            Destination::Path(path) => Some(path.clone()),
+            Destination::RemoteName { dir, .. } => output::url_file_name(url)
+                .ok()
+                .flatten()
+                .map(|file_name| dir.join(file_name)),
+        };
+        if let Some(part_path) = part_path {
+            return fetch_to_part_file(url, &part_path, destination, save_options, options);
+        }
+    }
+    let mut file = create_file(&redact_url(url), dir)?;
+    let response = fetch(url, &mut file, options, None)?;
+    save(url, file, destination, save_options, &response)?;
+    Ok(response)
+}
+
+/// Fetches each URL to its destination concurrently, returning the outcome of each fetch in the
+/// order given.
+fn fetch_to_files(
+    downloads: &[(String, Destination)],
+    save_options: &SaveOptions,
+    options: &Options,
+    parallel_max: usize,
+) -> Result<Vec<Result<Response>>> {
+    let mut files = downloads
+        .iter()
+        .map(|(url, destination)| {
+            create_file(&redact_url(url), output_dir(destination, save_options)?)
+        })
+        .collect::<Vec<_>>();
+    let requests = downloads
+        .iter()
+        .zip(files.iter_mut())
+        .filter_map(|((url, _), file)| Some((url.as_str(), file.as_mut().ok()?)))
+        .collect();
+    let mut fetched = fetch_all(requests, options, parallel_max)?.into_iter();
+    Ok(downloads
+        .iter()
+        .zip(files)
+        .map(|((url, destination), file)| {
+            let file = file?;
+            let response = fetched
+                .next()
+                .expect("There is a fetch result for every file opened.")?;
+            save(url, file, destination, save_options, &response)?;
+            Ok(response)
+        })
+        .collect())
+}
+
+fn fetch_to_part_file(
+    url: &str,
+    part_path: &Path,
+    destination: &Destination,
+    save_options: &SaveOptions,
+    options: &Options,
+) -> Result<Response> {
+    let (mut part_file, mut resume) = PartFile::open(part_path, url).with_context(|| {
+        Failure::new(
+            Category::Write,
+            format!(
+                "Failed to open a partial download for streaming {url} to.",
+                url = redact_url(url)
+            ),
+        )
+    })?;
+    if resume.offset > 0 {
+        eprintln!(
+            "Resuming the fetch of {url} from byte {offset}.",
+            url = redact_url(url),
+            offset = resume.offset
+        );
+    }
+    let result = match fetch_resumable(url, &mut part_file, options, &mut resume) {
+        Err(err) if err.downcast_ref::<ResumeRejected>().is_some() => {
+            eprintln!(
+                "{err}; restarting the fetch of {url}.",
+                url = redact_url(url)
+            );
+            part_file
+                .restart()
+                .context(Failure::new(Category::Write, "Failed to restart the fetch"))?;
+            resume = Resume::default();
+            fetch_resumable(url, &mut part_file, options, &mut resume)
+        }
+        result => result,
+    };
+    let response = match result {
+        Ok(response) if !response.not_modified => response,
+        result => {
+            if let Err(err) = part_file.save(&resume) {
+                eprintln!(
+                    "Failed to record the partial download of {url}: {err:#}",
+                    url = redact_url(url)
+                );
+            }
+            return result;
+        }
+    };
+    let path = destination_path(url, destination, &response)?;
+    save_options.apply(part_file.as_file(), &response)?;
+    part_file.commit(&path).context(Failure::new(
+        Category::Write,
+        "Failed to save the fetched content",
+    ))?;
+    Ok(response)
+}
+
+fn destination_path(url: &str, destination: &Destination, response: &Response) -> Result<PathBuf> {
+    Ok(match destination {
+        Destination::Path(path) => path.clone(),
+        Destination::RemoteName {
+            dir,
+            use_content_disposition,
+        } => dir.join(output::remote_file_name(
+            url,
+            response,
+            *use_content_disposition,
+        )?),
+    })
+}
+
+/// Parses a -z/--time-cond value: either an HTTP date or the path of a file whose modification
+/// time to use.
+///
+/// A path that does not exist yet yields no condition so that a first fetch is unconditional.
+fn parse_time_cond(value: &str) -> Result<Option<SystemTime>> {
+    if let Ok(time) = httpdate::parse_http_date(value) {
+        return Ok(Some(time));
+    }
+    match std::fs::metadata(value) {
+        Ok(metadata) => metadata
+            .modified()
+            .map(Some)
+            .with_context(|| format!("Failed to read the modification time of {value}")),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read metadata of {value}")),
+    }
+}
+
+/// Reads the ETag saved by a prior --etag-save, if any.
+fn read_etag(path: &str) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(etag) => Ok(Some(etag.trim().to_string()).filter(|etag| !etag.is_empty())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read the ETag from {path}")),
+    }
+}
+
+/// Saves the ETag of a fetch for use with a later --etag-compare.
+///
+/// A 304 Not Modified response leaves any previously saved ETag in place.
+fn save_etag(path: &str, response: &Response) -> Result<()> {
+    if response.not_modified {
+        return Ok(());
+    }
+    let etag = response
+        .etag
+        .as_deref()
+        .map(|etag| format!("{etag}\n"))
+        .unwrap_or_default();
+    std::fs::write(path, etag).with_context(|| {
+        Failure::new(
+            Category::Write,
+            format!("Failed to save the ETag to {path}"),
+        )
+    })
+}
+
+fn next_value(
+    args: &mut impl Iterator<Item = (usize, String)>,
+    program_name: &Option<String>,
+) -> String {
+    match args.next() {
+        Some((_, value)) => value,
+        None => usage(Category::Usage.exit_code(), program_name.clone()),
+    }
+}
+
+fn parse_value<T>(name: &str, value: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| invalid_usage(format!("Invalid value for {name} of {value:?}: {e}")))
+}
+
+fn open_lift_manifest(path: &str) -> Result<File> {
+    File::open(path).with_context(|| {
+        Failure::new(
+            Category::Config,
+            format!("Failed to open lift manifest at {path}"),
+        )
+    })
+}
+
+fn parse_mode(chmod: Option<String>, executable: bool) -> Result<Option<Mode>> {
+    Ok(match chmod {
+        Some(chmod) => Some(Mode::parse(&chmod).ok_or_else(|| {
+            invalid_usage(format!(
+                "Invalid value for --chmod of {chmod:?}: expected an octal mode."
+            ))
+        })?),
+        None => executable.then_some(Mode::Executable),
+    })
+}
+
+/// Parses a --write-out format, reading it from a file if given as `@path`, or stdin for `@-`.
+fn parse_write_out(format: &str) -> Result<WriteOut> {
+    let format = match format.strip_prefix('@') {
+        Some("-") => {
+            let mut format = String::new();
+            std::io::stdin()
+                .read_to_string(&mut format)
+                .map(|_| format)
+                .context("Failed to read the --write-out format from stdin")
+        }
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the --write-out format from {path}")),
+        None => Ok(format.to_string()),
+    }
+    .and_then(|format| WriteOut::parse(&format));
+    format.map_err(|err| invalid_usage(format!("{err:#}")))
+}
+
+/// Reads the URLs listed in a --url-file, each with the output path given for it, if any.
+fn read_url_file(path: &str) -> Result<Vec<(String, Option<String>)>> {
+    let mut content = String::new();
+    if path == "-" {
+        std::io::stdin().read_to_string(&mut content).map(|_| ())
+    } else {
+        std::fs::File::open(path).and_then(|mut file| file.read_to_string(&mut content).map(|_| ()))
+    }
+    .with_context(|| {
+        Failure::new(
+            Category::Usage,
+            format!("Failed to read the URL file {path}"),
+        )
+    })?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(url), output, None) => Ok((url.to_string(), output.map(str::to_string))),
+                _ => Err(invalid_usage(format!(
+                    "Invalid line in the URL file {path}: {line:?}; expected a URL optionally \
+                    followed by an output path."
+                ))),
+            }
+        })
+        .collect()
+}
+
+fn invalid_usage(message: String) -> anyhow::Error {
+    anyhow::Error::new(Failure::new(Category::Usage, message))
+}
+
+fn parse_seconds(name: &str, value: &str) -> Result<Duration> {
+    let seconds: f64 = parse_value(name, value)?;
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|e| invalid_usage(format!("Invalid value for {name} of {value:?}: {e}")))
+}
+
+/// Parses a --progress-interval of seconds or a percentage step; e.g.: 30 or 5%.
+fn parse_progress_interval(value: &str) -> Result<ProgressInterval> {
+    match value.strip_suffix('%') {
+        Some(percent) => match parse_value::<u8>("--progress-interval", percent)? {
+            percent @ 1..=100 => Ok(ProgressInterval::Percent(percent)),
+            _ => Err(invalid_usage(format!(
+                "Invalid value for --progress-interval of {value:?}: the percentage must be \
+                from 1 to 100."
+            ))),
+        },
+        None => Ok(ProgressInterval::Every(parse_seconds(
+            "--progress-interval",
+            value,
+        )?)),
+    }
+}
+
+/// Parses a byte count with an optional k, M, G or T suffix denoting powers of 1024.
+fn parse_size(name: &str, value: &str) -> Result<u64> {
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let multiplier: u64 = match suffix.to_ascii_lowercase() {
+                'k' => 1 << 10,
+                'm' => 1 << 20,
+                'g' => 1 << 30,
+                't' => 1 << 40,
+                _ => {
+                    return Err(invalid_usage(format!(
+                        "Invalid size suffix for {name} in {value:?}."
+                    )));
+                }
+            };
+            (&value[..index], multiplier)
+        }
+        _ => (value, 1),
+    };
+    let number: f64 = parse_value(name, number)?;
+    if !number.is_finite() {
+        return Err(invalid_usage(format!(
+            "Invalid value for {name} of {value:?}: must be a finite number."
+        )));
+    }
+    if number < 0.0 {
+        return Err(invalid_usage(format!(
+            "Invalid value for {name} of {value:?}: cannot be negative."
+        )));
+    }
+    let size = number * multiplier as f64;
+    // N.B.: u64::MAX rounds up to 2^64 as an f64; so this rejects exactly the sizes that would
+    // saturate.
+    if size >= u64::MAX as f64 {
+        return Err(invalid_usage(format!(
+            "Invalid value for {name} of {value:?}: too large."
+        )));
+    }
+    Ok(size as u64)
+}
+
+/// Parses an age with an optional s, m, h or d suffix; defaulting to seconds.
+fn parse_age(name: &str, value: &str) -> Result<Duration> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, suffix)) if suffix.is_ascii_alphabetic() => {
+            let unit: u64 = match suffix {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                'd' => 24 * 60 * 60,
+                _ => {
+                    return Err(invalid_usage(format!(
+                        "Invalid age suffix for {name} in {value:?}."
+                    )));
+                }
+            };
+            (&value[..index], unit)
+        }
+        _ => (value, 1),
+    };
+    parse_seconds(name, number)?
+        .checked_mul(unit as u32)
+        .ok_or_else(|| invalid_usage(format!("The {name} of {value:?} is too large.")))
+}
+
+fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    match seconds {
+        0..60 => format!("{seconds}s"),
+        60..3_600 => format!("{minutes}m", minutes = seconds / 60),
+        3_600..86_400 => format!("{hours}h", hours = seconds / 3_600),
+        _ => format!("{days}d", days = seconds / 86_400),
+    }
+}
+
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn cache_main(program_name: Option<String>, mut args: impl Iterator<Item = (usize, String)>) {
+    let mut command = None;
+    let mut cache_dir = None;
+    let mut max_age = None;
+    let mut max_size = None;
+    while let Some((_, arg)) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => usage(0, program_name),
+            "--cache-dir" => cache_dir = Some(next_value(&mut args, &program_name)),
+            "--max-age" => max_age = Some(next_value(&mut args, &program_name)),
+            "--max-size" => max_size = Some(next_value(&mut args, &program_name)),
+            "list" | "verify" | "prune" if command.is_none() => command = Some(arg),
+            _ => usage(Category::Usage.exit_code(), program_name),
+        }
+    }
+    let cache = Cache::new(
+        cache_dir
+            .or_else(|| env_value("PTEX_CACHE_DIR"))
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                invalid_usage(
+                    "No cache directory is configured. Use --cache-dir or set PTEX_CACHE_DIR."
+                        .to_string(),
+                )
+            })
+            .or_exit(),
+    );
+    match command.as_deref() {
+        Some("list") => {
+            let now = std::time::SystemTime::now();
+            for entry in cache.entries().or_exit() {
+                println!(
+                    "{hash} {size:>12} {age}",
+                    hash = entry.hash,
+                    size = entry.size,
+                    age = format_age(now.duration_since(entry.last_used).unwrap_or_default())
+                );
+            }
+        }
+        Some("verify") => {
+            for entry in cache.verify().or_exit() {
+                eprintln!("Removed corrupt cache entry {hash}.", hash = entry.hash);
+            }
+        }
+        Some("prune") if max_age.is_some() || max_size.is_some() => {
+            let max_age = max_age.map(|value| parse_age("--max-age", &value).or_exit());
+            let max_size = max_size.map(|value| parse_size("--max-size", &value).or_exit());
+            let pruned = cache.prune(max_age, max_size).or_exit();
+            eprintln!(
+                "Pruned {count} cache {entries} totalling {size} bytes.",
+                count = pruned.len(),
+                entries = if pruned.len() == 1 {
+                    "entry"
+                } else {
+                    "entries"
+                },
+                size = pruned.iter().map(|entry| entry.size).sum::<u64>()
+            );
+        }
+        _ => usage(Category::Usage.exit_code(), program_name),
+    }
+}
+
 fn main() {
     let mut program_name = None;
     let mut save_as_remote_name = false;
-    let mut show_headers = false;
-    let mut show_progress = true;
-    let mut headers = vec![];
+    let mut remote_header_name = false;
+    let mut output_paths = vec![];
+    let mut output_dir = None;
+    let mut url_file = None;
+    let mut parallel_max = None;
+    let mut prefetch_dest = None;
+    let mut write_out = None;
+    let mut progress = None;
+    let mut progress_interval = None;
+    let mut create_dirs = false;
+    let mut remote_time = false;
+    let mut continue_at = None;
+    let mut chmod = None;
+    let mut extract_dir = None;
+    let mut archive_type = None;
+    let mut strip_components = None;
+    let mut include = vec![];
+    let mut executable = false;
+    let mut time_cond = None;
+    let mut etag_save = None;
+    let mut etag_compare = None;
+    let mut options = Options {
+        show_progress: true,
+        ..Default::default()
+    };
+    let mut retry_count = None;
+    let mut retry_max_time = None;
+    let mut cache_dir = None;
+    let mut cacert = None;
+    let mut capath = None;
+    let mut cert = None;
+    let mut key = None;
+    let mut pinned_pubkey = None;
+    let mut connect_timeout = None;
+    let mut max_time = None;
+    let mut speed_limit = None;
+    let mut limit_rate = None;
+    let mut speed_time = None;
+    let mut verbose = false;
+    let mut trace = None;
     let mut positional_args = vec![];
     let mut args = env::args().enumerate();
     while let Some((index, arg)) = args.next() {
         if index == 0 {
             program_name = Some(arg)
+        } else if index == 1 && arg == "cache" {
+            return cache_main(program_name, args);
+        } else if index == 1 && arg == "prefetch" {
+            prefetch_dest = Some(None);
         } else {
             match arg.as_str() {
                 "-h" | "--help" => {
@@ -302,51 +1425,430 @@ fn main() {
                     std::process::exit(0);
                 }
                 "-O" | "--remote-name" => save_as_remote_name = true,
-                "-D" | "--dump-header" => show_headers = true,
-                "-H" | "--header" => {
-                    if let Some((_, header)) = args.next() {
-                        headers.push(header);
-                    } else {
-                        usage(1, program_name)
-                    }
+                "-J" | "--remote-header-name" => remote_header_name = true,
+                "-o" | "--output" => output_paths.push(next_value(&mut args, &program_name)),
+                "--url-file" => url_file = Some(next_value(&mut args, &program_name)),
+                "--dest" if prefetch_dest.is_some() => {
+                    prefetch_dest = Some(Some(next_value(&mut args, &program_name)))
+                }
+                "--parallel-max" => parallel_max = Some(next_value(&mut args, &program_name)),
+                "--output-dir" => output_dir = Some(next_value(&mut args, &program_name)),
+                "--create-dirs" => create_dirs = true,
+                "-R" | "--remote-time" => remote_time = true,
+                "--chmod" => chmod = Some(next_value(&mut args, &program_name)),
+                "-x" | "--executable" => executable = true,
+                "--extract" => extract_dir = Some(next_value(&mut args, &program_name)),
+                "--archive-type" => archive_type = Some(next_value(&mut args, &program_name)),
+                "--strip-components" => {
+                    strip_components = Some(next_value(&mut args, &program_name))
+                }
+                "--include" => include.push(next_value(&mut args, &program_name)),
+                "-C" | "--continue-at" => continue_at = Some(next_value(&mut args, &program_name)),
+                "-z" | "--time-cond" => time_cond = Some(next_value(&mut args, &program_name)),
+                "--etag-save" => etag_save = Some(next_value(&mut args, &program_name)),
+                "--etag-compare" => etag_compare = Some(next_value(&mut args, &program_name)),
+                "-D" | "--dump-header" => options.show_headers = true,
+                "-H" | "--header" => options.headers.push(next_value(&mut args, &program_name)),
+                "-s" | "--silent" => options.show_progress = false,
+                "-v" | "--verbose" => verbose = true,
+                "--trace" => trace = Some(next_value(&mut args, &program_name)),
+                "--progress" => progress = Some(next_value(&mut args, &program_name)),
+                "--progress-interval" => {
+                    progress_interval = Some(next_value(&mut args, &program_name))
+                }
+                flag if flag.starts_with("--progress=") => {
+                    progress = flag.strip_prefix("--progress=").map(str::to_string)
+                }
+                "--compressed" => options.compressed = true,
+                "--retry" => retry_count = Some(next_value(&mut args, &program_name)),
+                "--retry-max-time" => retry_max_time = Some(next_value(&mut args, &program_name)),
+                "--cache-dir" => cache_dir = Some(next_value(&mut args, &program_name)),
+                "--proxy" => options.proxy.url = Some(next_value(&mut args, &program_name)),
+                "--proxy-user" => options.proxy.user = Some(next_value(&mut args, &program_name)),
+                "--noproxy" => options.proxy.noproxy = Some(next_value(&mut args, &program_name)),
+                "--cacert" => cacert = Some(next_value(&mut args, &program_name)),
+                "--capath" => capath = Some(next_value(&mut args, &program_name)),
+                "--cert" => cert = Some(next_value(&mut args, &program_name)),
+                "--key" => key = Some(next_value(&mut args, &program_name)),
+                "--pinnedpubkey" => pinned_pubkey = Some(next_value(&mut args, &program_name)),
+                "--connect-timeout" => connect_timeout = Some(next_value(&mut args, &program_name)),
+                "--max-time" => max_time = Some(next_value(&mut args, &program_name)),
+                "--speed-limit" => speed_limit = Some(next_value(&mut args, &program_name)),
+                "--speed-time" => speed_time = Some(next_value(&mut args, &program_name)),
+                "--limit-rate" => limit_rate = Some(next_value(&mut args, &program_name)),
+                "-w" | "--write-out" => write_out = Some(next_value(&mut args, &program_name)),
+                "--proxy-cacert" => {
+                    options.proxy.cacert = Some(PathBuf::from(next_value(&mut args, &program_name)))
                 }
-                "-s" | "--silent" => show_progress = false,
                 _ => positional_args.push(arg),
             }
         }
     }
-    if !show_headers
+    if !options.show_headers
         && let Some(value) = env::var_os("PTEX_DUMP_HEADERS")
         && !value.is_empty()
     {
-        show_headers = true;
+        options.show_headers = true;
+    }
+    let progress_fd = env_value("PTEX_PROGRESS_FD");
+    let progress = progress.or_else(|| progress_fd.is_some().then(|| "json".to_string()));
+    let progress_interval = progress_interval
+        .or_else(|| env_value("PTEX_PROGRESS_INTERVAL"))
+        .map(|interval| parse_progress_interval(&interval))
+        .transpose()
+        .or_exit();
+    match progress.as_deref() {
+        Some("bar") => {}
+        // N.B.: A bar is not drawn when stderr is not a terminal; so we print lines instead, which
+        // show up in CI logs.
+        None if std::io::stderr().is_terminal() => {}
+        None | Some("lines") => {
+            if options.show_progress {
+                options.show_progress = false;
+                options.progress_lines = Some(
+                    progress_interval.unwrap_or(ProgressInterval::Every(DEFAULT_PROGRESS_INTERVAL)),
+                );
+            }
+        }
+        Some("json") => {
+            let events = match progress_fd {
+                Some(fd) => parse_value("PTEX_PROGRESS_FD", &fd)
+                    .and_then(|fd| {
+                        Events::to_fd(fd).map_err(|err| invalid_usage(format!("{err:#}")))
+                    })
+                    .or_exit(),
+                None => Events::stderr(),
+            };
+            // N.B.: The events replace the progress bar rather than competing with it on stderr.
+            options.show_progress = false;
+            options.events = Some(events);
+        }
+        Some(progress) => Err(invalid_usage(format!(
+            "Unsupported --progress of {progress}; expected bar, lines or json."
+        )))
+        .or_exit(),
+    }
+    if let Some(count) = retry_count.or_else(|| env_value("PTEX_RETRY")) {
+        options.retry = Retry {
+            count: parse_value("--retry", &count).or_exit(),
+            ..options.retry
+        };
+    }
+    if let Some(max_time) = retry_max_time.or_else(|| env_value("PTEX_RETRY_MAX_TIME")) {
+        options.retry = Retry {
+            max_time: Some(parse_seconds("--retry-max-time", &max_time).or_exit()),
+            ..options.retry
+        };
+    }
+    if let Some(connect_timeout) = connect_timeout.or_else(|| env_value("PTEX_CONNECT_TIMEOUT")) {
+        options.timeouts.connect =
+            Some(parse_seconds("--connect-timeout", &connect_timeout).or_exit());
+    }
+    if let Some(max_time) = max_time.or_else(|| env_value("PTEX_MAX_TIME")) {
+        options.timeouts.max_time = Some(parse_seconds("--max-time", &max_time).or_exit());
+    }
+    if let Some(speed_limit) = speed_limit.or_else(|| env_value("PTEX_SPEED_LIMIT")) {
+        let speed_limit = parse_size("--speed-limit", &speed_limit).or_exit();
+        options.timeouts.speed_limit = Some(
+            u32::try_from(speed_limit)
+                .map_err(|_| {
+                    invalid_usage(format!(
+                        "The --speed-limit of {speed_limit} bytes/s is too large."
+                    ))
+                })
+                .or_exit(),
+        );
+    }
+    if let Some(speed_time) = speed_time.or_else(|| env_value("PTEX_SPEED_TIME")) {
+        options.timeouts.speed_time = Some(parse_seconds("--speed-time", &speed_time).or_exit());
+    }
+    if let Some(limit_rate) = limit_rate.or_else(|| env_value("PTEX_LIMIT_RATE")) {
+        options.limit_rate = match parse_size("--limit-rate", &limit_rate).or_exit() {
+            // N.B.: As with curl, a rate of 0 means no limit.
+            0 => None,
+            limit_rate => Some(limit_rate),
+        };
+    }
+    options.trace = Trace::new(verbose, trace.as_deref())
+        .context(Failure::new(
+            Category::Write,
+            "Failed to open the --trace file",
+        ))
+        .or_exit();
+    options.tls = Tls {
+        cacert: cacert
+            .or_else(|| env_value("PTEX_CA_BUNDLE"))
+            .or_else(|| env_value("SSL_CERT_FILE"))
+            .map(PathBuf::from),
+        capath: capath
+            .or_else(|| env_value("PTEX_CA_PATH"))
+            .or_else(|| env_value("SSL_CERT_DIR"))
+            .map(PathBuf::from),
+        cert: cert
+            .or_else(|| env_value("PTEX_CLIENT_CERT"))
+            .map(PathBuf::from),
+        key: key
+            .or_else(|| env_value("PTEX_CLIENT_KEY"))
+            .map(PathBuf::from),
+        pinned_pubkey: pinned_pubkey.or_else(|| env_value("PTEX_PINNED_PUBKEY")),
+    };
+    let cache = cache_dir
+        .or_else(|| env_value("PTEX_CACHE_DIR"))
+        .map(|cache_dir| Cache::new(PathBuf::from(cache_dir)));
+
+    let parallel_max = match parallel_max {
+        Some(value) => parse_value::<NonZeroUsize>("--parallel-max", &value).or_exit(),
+        None => DEFAULT_PARALLEL_MAX,
+    };
+    let write_out = write_out
+        .map(|format| parse_write_out(&format))
+        .transpose()
+        .or_exit();
+    if let Some(dest) = prefetch_dest {
+        if write_out.is_some() {
+            Err(invalid_usage(
+                "-w only applies when fetching URLs.".to_string(),
+            ))
+            .or_exit()
+        }
+        let [lift_manifest_path] = &positional_args[..] else {
+            usage(Category::Usage.exit_code(), program_name)
+        };
+        let dest = dest
+            .ok_or_else(|| invalid_usage("prefetch requires --dest.".to_string()))
+            .or_exit();
+        let lift_manifest = open_lift_manifest(lift_manifest_path).or_exit();
+        // N.B.: Files are fetched concurrently; so we report each as it completes instead of
+        // drawing competing progress bars.
+        options.show_progress = false;
+        interrupt::install_handler().or_exit();
+        let prefetched = prefetch(
+            &lift_manifest,
+            Path::new(&dest),
+            &options,
+            cache.as_ref(),
+            parallel_max,
+        )
+        .or_exit();
+        let (mut fetched, mut skipped, mut failed) = (0, 0, 0);
+        let mut failure = None;
+        for (file_path, outcome) in prefetched {
+            let file_path = file_path.display();
+            match outcome {
+                Prefetched::Fetched => {
+                    fetched += 1;
+                    println!("Fetched {file_path}.");
+                }
+                Prefetched::Skipped(reason) => {
+                    skipped += 1;
+                    println!("Skipped {file_path}: {reason}.");
+                }
+                Prefetched::Failed(err) => {
+                    failed += 1;
+                    println!("Failed {file_path}: {err:#}");
+                    failure.get_or_insert(Category::of(&err));
+                }
+            }
+        }
+        println!("Fetched {fetched}, skipped {skipped} and failed {failed} files.");
+        if let Some(category) = failure {
+            std::process::exit(category.exit_code());
+        }
+        return;
     }
 
     match &positional_args[..] {
-        [lift_manifest_path, file_path] if !save_as_remote_name => {
-            let lift_manifest = std::fs::File::open(lift_manifest_path)
-                .with_context(|| format!("Failed to open lift manifest at {lift_manifest_path}"))
-                .or_exit();
+        [lift_manifest_path, file_path]
+            if !save_as_remote_name && output_paths.is_empty() && url_file.is_none() =>
+        {
+            if write_out.is_some() {
+                Err(invalid_usage(
+                    "-w only applies when fetching URLs.".to_string(),
+                ))
+                .or_exit()
+            }
+            let lift_manifest = open_lift_manifest(lift_manifest_path).or_exit();
             fetch_manifest(
                 &lift_manifest,
                 &PathBuf::from(file_path),
                 std::io::stdout(),
-                headers,
-                show_headers,
-                show_progress,
+                &options,
+                cache.as_ref(),
             )
             .or_exit()
         }
-        [url] => {
-            if save_as_remote_name {
-                let file = open_remote_filename(url).or_exit();
-                fetch(url, file, headers, show_headers, show_progress).or_exit();
+        [url] if output_paths.len() <= 1 && url_file.is_none() => {
+            let output_dir = output_dir.map(PathBuf::from);
+            let destination = match (
+                output_paths.first().map(String::as_str),
+                save_as_remote_name,
+            ) {
+                (Some(_), true) => usage(Category::Usage.exit_code(), program_name),
+                (Some("-"), false) | (None, false) => None,
+                // N.B.: Like curl, --output-dir only applies to relative output paths.
+                (Some(path), false) => Some(Destination::Path(match output_dir {
+                    Some(output_dir) => output_dir.join(path),
+                    None => PathBuf::from(path),
+                })),
+                (None, true) => Some(Destination::RemoteName {
+                    dir: output_dir.unwrap_or_else(|| PathBuf::from(".")),
+                    use_content_disposition: remote_header_name,
+                }),
+            };
+            match continue_at.as_deref() {
+                Some("-") if destination.is_none() => {
+                    Err(invalid_usage("-C - requires -O or -o.".to_string())).or_exit()
+                }
+                Some("-") | None => {}
+                Some(offset) => Err(invalid_usage(format!(
+                    "Only `-C -` is supported; cannot continue at {offset}."
+                )))
+                .or_exit(),
+            }
+            let mode = parse_mode(chmod, executable).or_exit();
+            if let Some(time_cond) = time_cond {
+                options.conditions.if_modified_since = parse_time_cond(&time_cond).or_exit();
+            }
+            if let Some(etag_compare) = etag_compare {
+                options.conditions.if_none_match = read_etag(&etag_compare).or_exit();
+            }
+            let response = if let Some(extract_dir) = extract_dir {
+                if destination.is_some() || continue_at.is_some() {
+                    Err(invalid_usage(
+                        "--extract cannot be combined with -O, -o or -C.".to_string(),
+                    ))
+                    .or_exit()
+                }
+                let archive_type = match archive_type {
+                    Some(archive_type) => ArchiveType::parse(&archive_type).ok_or_else(|| {
+                        invalid_usage(format!("Unsupported --archive-type of {archive_type}."))
+                    }),
+                    None => output::url_file_name(url)
+                        .ok()
+                        .flatten()
+                        .and_then(|file_name| ArchiveType::from_file_name(&file_name))
+                        .ok_or_else(|| {
+                            invalid_usage(format!(
+                                "Could not determine the archive type of {url}; use \
+                                --archive-type.",
+                                url = redact_url(url)
+                            ))
+                        }),
+                }
+                .or_exit();
+                let selection = Selection {
+                    strip_components: strip_components
+                        .map(|value| parse_value("--strip-components", &value))
+                        .transpose()
+                        .or_exit()
+                        .unwrap_or_default(),
+                    include,
+                };
+                interrupt::install_handler().or_exit();
+                let extraction = Extraction {
+                    dir: PathBuf::from(extract_dir),
+                    archive_type,
+                    selection,
+                    create_dirs,
+                };
+                extract::fetch_and_extract(url, &extraction, &options).or_exit()
+            } else if let Some(destination) = destination {
+                interrupt::install_handler().or_exit();
+                let save_options = SaveOptions {
+                    create_dirs,
+                    remote_time,
+                    resume: continue_at.is_some(),
+                    mode,
+                };
+                fetch_to_file(url, &destination, &save_options, &options).or_exit()
             } else {
-                fetch(url, std::io::stdout(), headers, show_headers, show_progress).or_exit();
+                fetch(url, std::io::stdout(), &options, None).or_exit()
+            };
+            if let Some(etag_save) = etag_save {
+                save_etag(&etag_save, &response).or_exit();
+            }
+            if let Some(write_out) = write_out {
+                write_out.write(url, &response).or_exit();
+            }
+        }
+        urls if !urls.is_empty() || url_file.is_some() => {
+            if continue_at.is_some()
+                || extract_dir.is_some()
+                || etag_save.is_some()
+                || etag_compare.is_some()
+            {
+                Err(invalid_usage(
+                    "-C, --extract, --etag-save and --etag-compare only support fetching a \
+                    single URL."
+                        .to_string(),
+                ))
+                .or_exit()
+            }
+            if save_as_remote_name && !output_paths.is_empty() {
+                usage(Category::Usage.exit_code(), program_name)
+            }
+            if !save_as_remote_name && output_paths.len() != urls.len() {
+                Err(invalid_usage(
+                    "Each URL needs its own -o when fetching several URLs; or use -O.".to_string(),
+                ))
+                .or_exit()
+            }
+            let output_dir = output_dir.map(PathBuf::from);
+            let destination = |output: Option<&str>| match output {
+                Some("-") => Err(invalid_usage(
+                    "Cannot write the output of several URLs to stdout.".to_string(),
+                )),
+                Some(path) => Ok(Destination::Path(match &output_dir {
+                    Some(output_dir) => output_dir.join(path),
+                    None => PathBuf::from(path),
+                })),
+                None => Ok(Destination::RemoteName {
+                    dir: output_dir.clone().unwrap_or_else(|| PathBuf::from(".")),
+                    use_content_disposition: remote_header_name,
+                }),
+            };
+            let mut downloads = vec![];
+            for (index, url) in urls.iter().enumerate() {
+                let output = output_paths.get(index).map(String::as_str);
+                downloads.push((url.clone(), destination(output).or_exit()));
+            }
+            if let Some(url_file) = url_file {
+                for (url, output) in read_url_file(&url_file).or_exit() {
+                    downloads.push((url, destination(output.as_deref()).or_exit()));
+                }
+            }
+            if let Some(time_cond) = time_cond {
+                options.conditions.if_modified_since = parse_time_cond(&time_cond).or_exit();
+            }
+            let save_options = SaveOptions {
+                create_dirs,
+                remote_time,
+                resume: false,
+                mode: parse_mode(chmod, executable).or_exit(),
+            };
+            interrupt::install_handler().or_exit();
+            let results =
+                fetch_to_files(&downloads, &save_options, &options, parallel_max.get()).or_exit();
+            let mut failure = None;
+            for ((url, _), result) in downloads.iter().zip(results) {
+                match result {
+                    Ok(response) => {
+                        if let Some(write_out) = &write_out {
+                            write_out.write(url, &response).or_exit();
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("{err:#}");
+                        failure.get_or_insert(Category::of(&err));
+                    }
+                }
+            }
+            if let Some(category) = failure {
+                std::process::exit(category.exit_code());
             }
         }
         _ => {
-            usage(1, program_name);
+            usage(Category::Usage.exit_code(), program_name);
         }
     }
 }
@@ -354,10 +1856,18 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use std::num::NonZeroUsize;
     use std::path::Path;
+    use std::time::Duration;
 
     use sha2::{Digest, Sha256};
 
+    use super::{Destination, Prefetched, SaveOptions};
+    use crate::cache::Cache;
+    use crate::error::Category;
+    use crate::fetch::Options;
+    use crate::fetch::tests::serve;
+
     const URL: &str =
         "https://github.com/a-scie/jump/releases/download/v0.2.1/scie-jump-linux-aarch64";
 
@@ -385,18 +1895,559 @@ mod tests {
             Cursor::new(manifest),
             Path::new("scie-jump"),
             &mut buffer,
-            vec![],
-            true,
-            true,
+            &Options {
+                show_headers: true,
+                show_progress: true,
+                ..Default::default()
+            },
+            None,
         )
         .unwrap();
         assert_fetched_buffer(buffer.as_slice());
     }
 
     #[test]
-    fn fetch() {
+    fn fetch_manifest_fingerprint_mismatch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let file = tempdir.path().join("file");
+        std::fs::write(&file, b"content").unwrap();
+        let url = url::Url::from_file_path(&file).unwrap();
+        let manifest = |size: usize, hash: &str| {
+            format!(
+                r#"
+{{
+    "scie": {{
+        "lift": {{
+            "files": [
+                {{
+                    "name": "file",
+                    "size": {size},
+                    "hash": "{hash}"
+                }}
+            ]
+        }}
+    }},
+    "ptex": {{
+        "file": "{url}"
+    }}
+}}
+"#
+            )
+        };
+        let fetch_manifest = |manifest: String| {
+            super::fetch_manifest(
+                Cursor::new(manifest),
+                Path::new("file"),
+                Vec::new(),
+                &Options::default(),
+                None,
+            )
+        };
+
+        let hash = hex::encode(Sha256::digest(b"content"));
+        fetch_manifest(manifest(7, &hash)).unwrap();
+
+        let err = fetch_manifest(manifest(8, &hash)).unwrap_err();
+        assert!(format!("{err:#}").contains("Size mismatch"), "{err:#}");
+
+        let err = fetch_manifest(manifest(7, &"0".repeat(64))).unwrap_err();
+        assert!(format!("{err:#}").contains("Hash mismatch"), "{err:#}");
+    }
+
+    #[test]
+    fn fetch_manifest_request_options() {
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ncontent".to_vec(),
+        ]);
+        let manifest = format!(
+            r#"
+{{
+    "ptex": {{
+        "file": {{
+            "url": "{url}",
+            "headers": {{
+                "Authorization": "Bearer file-token",
+                "X-File": "file"
+            }},
+            "netrc": "ignored",
+            "timeout": 10,
+            "compressed": true
+        }}
+    }}
+}}
+"#
+        );
         let mut buffer: Vec<u8> = Vec::new();
-        super::fetch(URL, &mut buffer, vec![], false, true).unwrap();
-        assert_fetched_buffer(buffer.as_slice());
+        super::fetch_manifest(
+            Cursor::new(manifest),
+            Path::new("file"),
+            &mut buffer,
+            &Options {
+                headers: vec![
+                    "authorization: Bearer global-token".to_string(),
+                    "X-Global: global".to_string(),
+                ],
+                ..Default::default()
+            },
+            None,
+        )
+        .unwrap();
+        assert_eq!(b"content".as_slice(), buffer.as_slice());
+
+        let request = requests.recv().unwrap();
+        assert!(request.contains("X-Global: global\r\n"), "{request}");
+        assert!(request.contains("X-File: file\r\n"), "{request}");
+        assert!(
+            request.contains("Authorization: Bearer file-token\r\n"),
+            "{request}"
+        );
+        assert!(!request.contains("global-token"), "{request}");
+        assert!(request.contains("Accept-Encoding: "), "{request}");
+    }
+
+    #[test]
+    fn fetch_manifest_cache() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let file = tempdir.path().join("file");
+        std::fs::write(&file, b"content").unwrap();
+        let url = url::Url::from_file_path(&file).unwrap();
+        let hash = hex::encode(Sha256::digest(b"content"));
+        let manifest = format!(
+            r#"
+{{
+    "scie": {{
+        "lift": {{
+            "files": [
+                {{
+                    "name": "file",
+                    "size": 7,
+                    "hash": "{hash}"
+                }}
+            ]
+        }}
+    }},
+    "ptex": {{
+        "file": "{url}"
+    }}
+}}
+"#
+        );
+        let cache = Cache::new(tempdir.path().join("cache"));
+        let fetch_manifest = || {
+            let mut buffer: Vec<u8> = Vec::new();
+            super::fetch_manifest(
+                Cursor::new(&manifest),
+                Path::new("file"),
+                &mut buffer,
+                &Options::default(),
+                Some(&cache),
+            )
+            .map(|_| buffer)
+        };
+
+        assert_eq!(b"content".as_slice(), fetch_manifest().unwrap().as_slice());
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(b"content".as_slice(), fetch_manifest().unwrap().as_slice());
+        let entries = cache.entries().unwrap();
+        assert_eq!(
+            vec![hash.clone()],
+            entries
+                .iter()
+                .map(|entry| entry.hash.clone())
+                .collect::<Vec<_>>()
+        );
+
+        // A corrupt entry of the right size is evicted and re-fetched.
+        std::fs::write(&entries[0].path, b"CONTENT").unwrap();
+        std::fs::write(&file, b"content").unwrap();
+        assert_eq!(b"content".as_slice(), fetch_manifest().unwrap().as_slice());
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(&entries[0].path).unwrap()
+        );
+    }
+
+    #[test]
+    fn fetch_manifest_mirrors() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let file = tempdir.path().join("file");
+        std::fs::write(&file, b"content").unwrap();
+        let missing = url::Url::from_file_path(tempdir.path().join("missing")).unwrap();
+        let url = url::Url::from_file_path(&file).unwrap();
+        let fetch_manifest = |urls: &str| {
+            let manifest = format!(
+                r#"
+{{
+    "ptex": {{
+        "file": [{urls}]
+    }}
+}}
+"#
+            );
+            let mut buffer: Vec<u8> = Vec::new();
+            super::fetch_manifest(
+                Cursor::new(manifest),
+                Path::new("file"),
+                &mut buffer,
+                &Options::default(),
+                None,
+            )
+            .map(|_| buffer)
+        };
+
+        assert_eq!(
+            b"content".as_slice(),
+            fetch_manifest(&format!(r#""{missing}", "{url}""#))
+                .unwrap()
+                .as_slice()
+        );
+        assert_eq!(
+            b"content".as_slice(),
+            fetch_manifest(&format!(r#""{url}", "{missing}""#))
+                .unwrap()
+                .as_slice()
+        );
+
+        let err = fetch_manifest(&format!(r#""{missing}", "{missing}""#)).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.contains(&format!(
+                "Failed to fetch from all 2 URLs tried:\n  {missing}\n  {missing}"
+            )),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn parse_age() {
+        assert_eq!(
+            Duration::from_secs(90),
+            super::parse_age("--max-age", "1.5m").unwrap()
+        );
+        assert_eq!(
+            Duration::from_secs(2 * 24 * 60 * 60),
+            super::parse_age("--max-age", "2d").unwrap()
+        );
+        assert!(super::parse_age("--max-age", "1000000000000000d").is_err());
+        assert!(super::parse_age("--max-age", "1w").is_err());
+    }
+
+    #[test]
+    fn fetch_to_file_remote_name() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let fetch_to_file = |use_content_disposition: bool| {
+            let (url, _requests) = serve(vec![
+                b"HTTP/1.1 302 Found\r\nLocation: /assets/tool%20v1.tar.gz\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_vec(),
+                b"HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"tool.tar.gz\"\r\n\
+                Content-Length: 7\r\nConnection: close\r\n\r\ncontent"
+                    .to_vec(),
+            ]);
+            super::fetch_to_file(
+                &url,
+                &Destination::RemoteName {
+                    dir: tempdir.path().to_path_buf(),
+                    use_content_disposition,
+                },
+                &SaveOptions::default(),
+                &Options::default(),
+            )
+            .unwrap();
+        };
+
+        fetch_to_file(false);
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(tempdir.path().join("tool v1.tar.gz"))
+                .unwrap()
+                .as_slice()
+        );
+
+        fetch_to_file(true);
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(tempdir.path().join("tool.tar.gz"))
+                .unwrap()
+                .as_slice()
+        );
+    }
+
+    #[test]
+    fn fetch_to_file_conditional() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        let etag_path = tempdir.path().join("etag");
+        let etag_path = etag_path.to_str().unwrap();
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+            ETag: \"v1\"\r\nContent-Length: 7\r\n\r\ncontent"
+                .to_vec(),
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_vec(),
+        ]);
+        let save_options = SaveOptions {
+            remote_time: true,
+            ..Default::default()
+        };
+
+        let response = super::fetch_to_file(
+            &url,
+            &Destination::Path(path.clone()),
+            &save_options,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(!response.not_modified);
+        super::save_etag(etag_path, &response).unwrap();
+        requests.recv().unwrap();
+        let last_modified = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            last_modified,
+            std::fs::metadata(&path).unwrap().modified().unwrap()
+        );
+
+        let mut options = Options::default();
+        options.conditions.if_modified_since =
+            super::parse_time_cond(path.to_str().unwrap()).unwrap();
+        options.conditions.if_none_match = super::read_etag(etag_path).unwrap();
+        let response = super::fetch_to_file(
+            &url,
+            &Destination::Path(path.clone()),
+            &save_options,
+            &options,
+        )
+        .unwrap();
+        assert!(response.not_modified);
+        let request = requests.recv().unwrap();
+        assert!(
+            request.contains("If-Modified-Since: Wed, 21 Oct 2015 07:28:00 GMT\r\n"),
+            "{request}"
+        );
+        assert!(request.contains("If-None-Match: \"v1\"\r\n"), "{request}");
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(&path).unwrap().as_slice()
+        );
+        // Just the file and the saved ETag; the empty temporary file was discarded.
+        assert_eq!(2, std::fs::read_dir(tempdir.path()).unwrap().count());
+
+        assert_eq!(
+            None,
+            super::parse_time_cond(tempdir.path().join("missing").to_str().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn fetch_to_file_resume() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        let part_path = tempdir.path().join("file.ptex-part");
+        let sidecar_path = tempdir.path().join("file.ptex-part.json");
+        let save_options = SaveOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let fetch_to_file = |url: &str| {
+            super::fetch_to_file(
+                url,
+                &Destination::Path(path.clone()),
+                &save_options,
+                &Options::default(),
+            )
+        };
+
+        // A transfer cut short leaves the partial download and its state behind.
+        let (url, _requests) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 7\r\n\r\ncon".to_vec(),
+        ]);
+        assert!(fetch_to_file(&url).is_err());
+        assert!(!path.exists());
+        assert_eq!(
+            b"con".as_slice(),
+            std::fs::read(&part_path).unwrap().as_slice()
+        );
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&sidecar_path).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({"url": url, "validator": "\"v1\"", "received": 3}),
+            sidecar
+        );
+
+        // The next run picks up where the last left off.
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 206 Partial Content\r\nETag: \"v1\"\r\n\
+            Content-Range: bytes 3-6/7\r\nContent-Length: 4\r\n\r\ntent"
+                .to_vec(),
+        ]);
+        std::fs::write(
+            &sidecar_path,
+            serde_json::json!({"url": url, "validator": "\"v1\"", "received": 3}).to_string(),
+        )
+        .unwrap();
+        fetch_to_file(&url).unwrap();
+        let request = requests.recv().unwrap();
+        assert!(request.contains("Range: bytes=3-\r\n"), "{request}");
+        assert!(request.contains("If-Range: \"v1\"\r\n"), "{request}");
+        assert_eq!(
+            b"content".as_slice(),
+            std::fs::read(&path).unwrap().as_slice()
+        );
+        assert!(!part_path.exists());
+        assert!(!sidecar_path.exists());
+
+        // A changed file is fetched from scratch.
+        let (url, requests) = serve(vec![
+            b"HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 7\r\n\r\nchanged".to_vec(),
+            b"HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nContent-Length: 7\r\n\r\nchanged".to_vec(),
+        ]);
+        std::fs::write(&part_path, b"con").unwrap();
+        std::fs::write(
+            &sidecar_path,
+            serde_json::json!({"url": url, "validator": "\"v1\"", "received": 3}).to_string(),
+        )
+        .unwrap();
+        fetch_to_file(&url).unwrap();
+        assert!(requests.recv().unwrap().contains("Range: bytes=3-\r\n"));
+        assert!(!requests.recv().unwrap().contains("Range:"));
+        assert_eq!(
+            b"changed".as_slice(),
+            std::fs::read(&path).unwrap().as_slice()
+        );
+        assert!(!part_path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fetch_to_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::output::Mode;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let source = tempdir.path().join("source");
+        std::fs::write(&source, "content").unwrap();
+        let url = url::Url::from_file_path(&source).unwrap();
+        let fetch_to_file = |name: &str, mode: Option<Mode>| {
+            let path = tempdir.path().join(name);
+            super::fetch_to_file(
+                url.as_str(),
+                &Destination::Path(path.clone()),
+                &SaveOptions {
+                    mode,
+                    ..Default::default()
+                },
+                &Options::default(),
+            )
+            .unwrap();
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+        };
+
+        let default_mode = fetch_to_file("default", None);
+        assert_eq!(0, default_mode & 0o111);
+        assert_eq!(
+            default_mode | ((default_mode & 0o444) >> 2),
+            fetch_to_file("executable", Some(Mode::Executable))
+        );
+        assert_eq!(0o750, fetch_to_file("exact", Mode::parse("750")));
+        assert!(Mode::parse("8").is_none());
+        assert!(Mode::parse("17777").is_none());
+    }
+
+    #[test]
+    fn prefetch() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let source = tempdir.path().join("source");
+        std::fs::write(&source, b"content").unwrap();
+        let url = url::Url::from_file_path(&source).unwrap();
+        let missing = url::Url::from_file_path(tempdir.path().join("missing")).unwrap();
+        let hash = hex::encode(Sha256::digest(b"content"));
+        let manifest = format!(
+            r#"
+{{
+    "scie": {{
+        "lift": {{
+            "files": [
+                {{"name": "ptex-linux-x86_64", "key": "ptex", "executable": true}},
+                {{"name": "embedded"}},
+                {{"name": "other", "source": "other"}},
+                {{"name": "fetched", "size": 7, "hash": "{hash}", "source": "fetch"}},
+                {{"name": "nested/fetched", "source": "fetch"}},
+                {{"name": "present", "size": 7, "hash": "{hash}", "source": "fetch"}},
+                {{"name": "stale", "size": 7, "hash": "{hash}", "source": "fetch"}},
+                {{"name": "unmapped", "source": "fetch"}},
+                {{"name": "missing", "source": "fetch"}},
+                {{"name": "../escape", "source": "fetch"}}
+            ],
+            "boot": {{
+                "bindings": {{
+                    "fetch": {{"exe": "{{ptex}}", "args": ["{{scie.lift}}"]}},
+                    "other": {{"exe": "{{embedded}}"}}
+                }}
+            }}
+        }}
+    }},
+    "ptex": {{
+        "embedded": "{url}",
+        "other": "{url}",
+        "fetched": "{url}",
+        "nested/fetched": "{url}",
+        "present": "{missing}",
+        "stale": "{url}",
+        "missing": "{missing}",
+        "../escape": "{url}"
+    }}
+}}
+"#
+        );
+        let dest = tempdir.path().join("dest");
+        std::fs::create_dir(&dest).unwrap();
+        std::fs::write(dest.join("present"), b"content").unwrap();
+        std::fs::write(dest.join("stale"), b"CONTENT").unwrap();
+
+        let outcomes = super::prefetch(
+            Cursor::new(manifest),
+            &dest,
+            &Options::default(),
+            None,
+            NonZeroUsize::new(2).unwrap(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(file_path, outcome)| {
+            let outcome = match outcome {
+                Prefetched::Fetched => "fetched".to_string(),
+                Prefetched::Skipped(reason) => format!("skipped: {reason}"),
+                Prefetched::Failed(err) => format!("failed: {:?}", Category::of(&err)),
+            };
+            (file_path.display().to_string(), outcome)
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("fetched".to_string(), "fetched".to_string()),
+                ("nested/fetched".to_string(), "fetched".to_string()),
+                (
+                    "present".to_string(),
+                    "skipped: it is already present".to_string()
+                ),
+                ("stale".to_string(), "fetched".to_string()),
+                (
+                    "unmapped".to_string(),
+                    "skipped: ptex has no URL mapping for it".to_string()
+                ),
+                ("missing".to_string(), "failed: Other".to_string()),
+                ("../escape".to_string(), "failed: Config".to_string()),
+            ],
+            outcomes
+        );
+        for name in ["fetched", "nested/fetched", "present", "stale"] {
+            assert_eq!(
+                b"content".as_slice(),
+                std::fs::read(dest.join(name)).unwrap()
+            );
+        }
+        assert!(!dest.join("embedded").exists());
+        assert!(!dest.join("other").exists());
+        assert!(!tempdir.path().join("escape").exists());
     }
 }
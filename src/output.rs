@@ -0,0 +1,570 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use url::Url;
+
+use crate::error::{Category, Failure};
+use crate::fetch::{Response, Resume};
+use crate::interrupt::Pending;
+use crate::redact::redact_url;
+
+/// A file that is written to a temporary file in the destination directory and only moved into
+/// place once committed.
+///
+/// This ensures an existing file at the destination is never clobbered by a failed or interrupted
+/// download. If the `AtomicFile` is dropped without being committed, the temporary file is
+/// removed.
+pub(crate) struct AtomicFile {
+    file: NamedTempFile,
+    _pending: Pending,
+}
+
+impl AtomicFile {
+    /// Creates a temporary file in `dir`; the file must later be committed to a path in `dir`.
+    pub(crate) fn create_in(dir: &Path) -> Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".ptex.").suffix(".tmp");
+        // N.B.: Temporary files are private by default, but we want the file to end up with the
+        // same permissions as any other newly created file.
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+        let file = builder.tempfile_in(dir).with_context(|| {
+            format!(
+                "Failed to create a temporary file in {dir}",
+                dir = dir.display()
+            )
+        })?;
+        let pending = Pending::register(file.path());
+        Ok(Self {
+            file,
+            _pending: pending,
+        })
+    }
+
+    pub(crate) fn as_file(&self) -> &File {
+        self.file.as_file()
+    }
+
+    /// Flushes the content to disk and atomically moves it into place at `path`.
+    pub(crate) fn commit(self, path: &Path) -> Result<()> {
+        self.file
+            .as_file()
+            .sync_all()
+            .with_context(|| format!("Failed to flush {path} to disk", path = path.display()))?;
+        self.file
+            .persist(path)
+            .with_context(|| format!("Failed to move {path} into place", path = path.display()))?;
+        sync_dir(parent_dir(path));
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A partially downloaded file that is kept across runs so that its download can be resumed.
+///
+/// The partial content is stored at `<name>.ptex-part` alongside its final path, and a
+/// `<name>.ptex-part.json` sidecar records the URL it is being fetched from, the bytes received
+/// and the validator identifying the version of the file those bytes belong to.
+pub(crate) struct PartFile {
+    path: PathBuf,
+    sidecar_path: PathBuf,
+    url: String,
+    file: File,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Sidecar {
+    url: String,
+    validator: Option<String>,
+    received: u64,
+}
+
+impl PartFile {
+    /// Opens the partial download for `path`, picking up from where a prior run left off if it
+    /// was fetching the same URL and recorded a validator.
+    pub(crate) fn open(path: &Path, url: &str) -> Result<(Self, Resume)> {
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("The path {path} has no file name.", path = path.display()))?;
+        let part_path = path.with_file_name(format!(
+            "{name}.ptex-part",
+            name = file_name.to_string_lossy()
+        ));
+        let sidecar_path = path.with_file_name(format!(
+            "{name}.ptex-part.json",
+            name = file_name.to_string_lossy()
+        ));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part_path)
+            .with_context(|| {
+                format!(
+                    "Failed to open the partial download {path}",
+                    path = part_path.display()
+                )
+            })?;
+        let length = file
+            .metadata()
+            .with_context(|| {
+                format!(
+                    "Failed to read metadata of {path}",
+                    path = part_path.display()
+                )
+            })?
+            .len();
+        let resume = match std::fs::read(&sidecar_path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<Sidecar>(&contents).ok())
+        {
+            Some(sidecar) if sidecar.url == url && sidecar.validator.is_some() => Resume {
+                // N.B.: The part file may hold more bytes than were recorded if we were killed
+                // before updating the sidecar; but never fewer unless it was tampered with.
+                offset: sidecar.received.min(length),
+                validator: sidecar.validator,
+            },
+            _ => Resume::default(),
+        };
+        file.set_len(resume.offset)
+            .and_then(|()| file.seek(SeekFrom::End(0)))
+            .with_context(|| {
+                format!(
+                    "Failed to prepare the partial download {path}",
+                    path = part_path.display()
+                )
+            })?;
+        let part_file = Self {
+            path: part_path,
+            sidecar_path,
+            url: url.to_string(),
+            file,
+        };
+        Ok((part_file, resume))
+    }
+
+    /// Discards any partial content so the download can start over.
+    pub(crate) fn restart(&mut self) -> Result<()> {
+        self.file
+            .set_len(0)
+            .and_then(|()| self.file.seek(SeekFrom::Start(0)))
+            .map(|_| ())
+            .with_context(|| {
+                format!(
+                    "Failed to truncate the partial download {path}",
+                    path = self.path.display()
+                )
+            })
+    }
+
+    /// Records the progress of the download so that a later run can resume it, or removes the
+    /// partial download if nothing was fetched.
+    pub(crate) fn save(self, resume: &Resume) -> Result<()> {
+        if resume.offset == 0 {
+            drop(self.file);
+            let _ = std::fs::remove_file(&self.sidecar_path);
+            return std::fs::remove_file(&self.path).with_context(|| {
+                format!(
+                    "Failed to remove the empty partial download {path}",
+                    path = self.path.display()
+                )
+            });
+        }
+        self.file.sync_data().with_context(|| {
+            format!("Failed to flush {path} to disk", path = self.path.display())
+        })?;
+        let sidecar = Sidecar {
+            url: self.url.clone(),
+            validator: resume.validator.clone(),
+            received: resume.offset,
+        };
+        let contents = serde_json::to_vec(&sidecar)
+            .context("Failed to serialize the partial download state")?;
+        std::fs::write(&self.sidecar_path, contents).with_context(|| {
+            format!(
+                "Failed to record the partial download state in {path}",
+                path = self.sidecar_path.display()
+            )
+        })
+    }
+
+    pub(crate) fn as_file(&self) -> &File {
+        &self.file
+    }
+
+    /// Flushes the completed download to disk and atomically moves it into place at `path`.
+    pub(crate) fn commit(self, path: &Path) -> Result<()> {
+        self.file
+            .sync_all()
+            .with_context(|| format!("Failed to flush {path} to disk", path = path.display()))?;
+        drop(self.file);
+        std::fs::rename(&self.path, path)
+            .with_context(|| format!("Failed to move {path} into place", path = path.display()))?;
+        sync_dir(parent_dir(path));
+        // N.B.: A stale sidecar is harmless since it will not match an empty part file.
+        let _ = std::fs::remove_file(&self.sidecar_path);
+        Ok(())
+    }
+}
+
+impl Write for PartFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Makes renames in `dir` durable.
+///
+/// Not all platforms support syncing directories; so this is best-effort.
+fn sync_dir(dir: &Path) {
+    if cfg!(unix) {
+        let _ = File::open(dir).and_then(|dir| dir.sync_all());
+    }
+}
+
+/// A file mode to apply to a fetched file.
+#[derive(Clone, Copy)]
+pub(crate) enum Mode {
+    /// Make the file executable by everyone who can read it.
+    Executable,
+    /// Set exactly these permission bits.
+    Exact(u32),
+}
+
+impl Mode {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        u32::from_str_radix(value, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .map(Mode::Exact)
+    }
+
+    /// Applies the mode to `file`, warning instead on platforms without file modes.
+    pub(crate) fn apply(self, file: &File) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = match self {
+                Mode::Executable => {
+                    let mode = file
+                        .metadata()
+                        .context("Failed to read the permissions of the fetched file")?
+                        .permissions()
+                        .mode();
+                    mode | ((mode & 0o444) >> 2)
+                }
+                Mode::Exact(mode) => mode,
+            };
+            file.set_permissions(std::fs::Permissions::from_mode(mode))
+                .context("Failed to set the permissions of the fetched file")
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = file;
+            eprintln!("Ignoring the requested file mode since file modes do not apply here.");
+            Ok(())
+        }
+    }
+}
+
+/// Returns the directory containing `path`, which is `.` for a bare file name.
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Derives the local file name to save the content fetched from `url` to.
+///
+/// When `use_content_disposition` is set, a file name suggested by the server via a
+/// Content-Disposition header takes precedence. Otherwise the last path segment of the URL the
+/// content was ultimately fetched from after redirects is used, falling back to that of `url`.
+pub(crate) fn remote_file_name(
+    url: &str,
+    response: &Response,
+    use_content_disposition: bool,
+) -> Result<String> {
+    if use_content_disposition
+        && let Some(file_name) = response
+            .content_disposition
+            .as_deref()
+            .and_then(content_disposition_file_name)
+    {
+        return check_file_name(&file_name, "the server's Content-Disposition header");
+    }
+    for url in [response.effective_url.as_str(), url] {
+        if let Some(file_name) = url_file_name(url)? {
+            return Ok(file_name);
+        }
+    }
+    Err(anyhow::Error::new(Failure::new(
+        Category::Url,
+        format!(
+            "Could not determine the remote file name of {url}",
+            url = redact_url(url)
+        ),
+    )))
+}
+
+pub(crate) fn url_file_name(url: &str) -> Result<Option<String>> {
+    let parsed_url = Url::parse(url).with_context(|| {
+        Failure::new(
+            Category::Url,
+            format!("Invalid URL {url}", url = redact_url(url)),
+        )
+    })?;
+    let Some(segment) = parsed_url
+        .path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+    else {
+        return Ok(None);
+    };
+    let file_name = percent_decode_str(segment).decode_utf8_lossy();
+    check_file_name(&file_name, url).map(Some)
+}
+
+/// Ensures a file name supplied by a remote source cannot be used to write outside the output
+/// directory.
+fn check_file_name(file_name: &str, source: &str) -> Result<String> {
+    if file_name.is_empty()
+        || file_name == "."
+        || file_name == ".."
+        || file_name
+            .chars()
+            .any(|char| char == '/' || char == '\\' || char.is_control())
+    {
+        return Err(anyhow::Error::new(Failure::new(
+            Category::Url,
+            format!("Refusing to save to the unsafe file name {file_name:?} from {source}."),
+        )));
+    }
+    Ok(file_name.to_string())
+}
+
+/// Extracts the file name from a Content-Disposition header value, preferring an RFC 5987
+/// encoded `filename*` parameter over a plain `filename` parameter.
+fn content_disposition_file_name(value: &str) -> Option<String> {
+    let parameters = parse_parameters(value);
+    let parameter = |name: &str| {
+        parameters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+    parameter("filename*")
+        .and_then(decode_ext_value)
+        .or_else(|| parameter("filename").map(str::to_string))
+}
+
+/// Parses the `; name=value` parameters following the disposition type, unquoting quoted values.
+fn parse_parameters(value: &str) -> Vec<(String, String)> {
+    let mut parameters = vec![];
+    let mut chars = value.chars().peekable();
+    // Skip the disposition type.
+    for char in chars.by_ref() {
+        if char == ';' {
+            break;
+        }
+    }
+    loop {
+        let name: String = chars
+            .by_ref()
+            .take_while(|char| *char != '=')
+            .collect::<String>();
+        let name = name.trim();
+        if name.is_empty() {
+            return parameters;
+        }
+        while chars.next_if(|char| char.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(char) = chars.next() {
+                match char {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    _ => value.push(char),
+                }
+            }
+            for char in chars.by_ref() {
+                if char == ';' {
+                    break;
+                }
+            }
+        } else {
+            value.extend(chars.by_ref().take_while(|char| *char != ';'));
+        }
+        parameters.push((name.to_string(), value.trim().to_string()));
+    }
+}
+
+/// Decodes an RFC 5987 `charset'language'percent-encoded` extended parameter value.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let bytes: Vec<u8> = percent_decode_str(parts.next()?).collect();
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{AtomicFile, content_disposition_file_name, remote_file_name};
+    use crate::fetch::Response;
+
+    #[test]
+    fn commit() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        std::fs::write(&path, b"original").unwrap();
+
+        let mut file = AtomicFile::create_in(tempdir.path()).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(b"original", std::fs::read(&path).unwrap().as_slice());
+        file.commit(&path).unwrap();
+        assert_eq!(b"new", std::fs::read(&path).unwrap().as_slice());
+        assert_eq!(1, std::fs::read_dir(tempdir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn abandon() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("file");
+        std::fs::write(&path, b"original").unwrap();
+
+        let mut file = AtomicFile::create_in(tempdir.path()).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert_eq!(b"original", std::fs::read(&path).unwrap().as_slice());
+        assert_eq!(1, std::fs::read_dir(tempdir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn content_disposition() {
+        assert_eq!(
+            Some("tool.tar.gz".to_string()),
+            content_disposition_file_name("attachment; filename=tool.tar.gz")
+        );
+        assert_eq!(
+            Some("my \"tool\".tar.gz".to_string()),
+            content_disposition_file_name(r#"attachment; filename="my \"tool\".tar.gz""#)
+        );
+        assert_eq!(
+            Some("t\u{f6}\u{f6}l.tar.gz".to_string()),
+            content_disposition_file_name(
+                "attachment; filename=\"tool.tar.gz\"; filename*=UTF-8''t%C3%B6%C3%B6l.tar.gz"
+            )
+        );
+        assert_eq!(
+            Some("t\u{f6}\u{f6}l.tar.gz".to_string()),
+            content_disposition_file_name("attachment; filename*=iso-8859-1'en't%F6%F6l.tar.gz")
+        );
+        assert_eq!(
+            Some("fallback".to_string()),
+            content_disposition_file_name("attachment; filename*=KOI8-R''x; filename=fallback")
+        );
+        assert_eq!(None, content_disposition_file_name("inline"));
+    }
+
+    #[test]
+    fn remote_name() {
+        let response = |effective_url: &str, content_disposition: Option<&str>| Response {
+            effective_url: effective_url.to_string(),
+            content_disposition: content_disposition.map(str::to_string),
+            ..Default::default()
+        };
+        let name = |url: &str, response: Response, use_content_disposition: bool| {
+            remote_file_name(url, &response, use_content_disposition)
+        };
+
+        let url = "https://example.org/releases/my%20tool.tar.gz";
+        assert_eq!(
+            "my tool.tar.gz",
+            name(url, response(url, None), false).unwrap()
+        );
+        assert_eq!(
+            "tool",
+            name(
+                "https://example.org/tool/",
+                response("https://example.org/tool/", None),
+                false
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            "asset.tar.gz",
+            name(
+                "https://example.org/download?id=123",
+                response("https://cdn.example.org/asset.tar.gz?sig=abc", None),
+                false
+            )
+            .unwrap()
+        );
+
+        let url = "https://example.org/download?id=123";
+        let disposition = response(url, Some("attachment; filename=\"asset.tar.gz\""));
+        assert_eq!("download", name(url, disposition, false).unwrap());
+        let disposition = response(url, Some("attachment; filename=\"asset.tar.gz\""));
+        assert_eq!("asset.tar.gz", name(url, disposition, true).unwrap());
+
+        for unsafe_name in ["../evil", "..", "a/b", "a\\b", ".bin/x"] {
+            let disposition = response(url, Some(&format!("attachment; filename={unsafe_name}")));
+            assert!(name(url, disposition, true).is_err(), "{unsafe_name}");
+        }
+        assert!(
+            name(
+                "https://example.org/a%2Fb",
+                response("https://example.org/a%2Fb", None),
+                false
+            )
+            .is_err()
+        );
+        assert!(
+            name(
+                "https://example.org/%2e%2e",
+                response("https://example.org/%2e%2e", None),
+                false
+            )
+            .is_err()
+        );
+        assert!(
+            name(
+                "https://example.org/",
+                response("https://example.org/", None),
+                false
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::env;
+
+use anyhow::{Result, anyhow, bail};
+
+const ENV_PREFIX: &str = "scie.env.";
+
+/// Expands `{scie.env.NAME}` and `{scie.env.NAME=default}` placeholders using the current
+/// environment.
+///
+/// The syntax matches that of the scie-jump lift manifest: defaults may themselves contain
+/// placeholders, `{{` escapes a literal `{` and any other placeholders are left as-is.
+pub(crate) fn expand(text: &str) -> Result<String> {
+    expand_with(text, &|name| match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(anyhow!(
+            "The environment variable {name} is not valid UTF-8."
+        )),
+    })
+}
+
+fn expand_with(text: &str, lookup: &dyn Fn(&str) -> Result<Option<String>>) -> Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(remaining) = rest.strip_prefix("{{") {
+            expanded.push('{');
+            rest = remaining;
+            continue;
+        }
+        let end =
+            matching_brace(rest).ok_or_else(|| anyhow!("Unterminated placeholder in {text:?}."))?;
+        let placeholder = &rest[1..end];
+        if let Some(variable) = placeholder.strip_prefix(ENV_PREFIX) {
+            let (name, default) = match variable.split_once('=') {
+                Some((name, default)) => (name, Some(default)),
+                None => (variable, None),
+            };
+            if name.is_empty() {
+                bail!("The placeholder {{{placeholder}}} in {text:?} is missing a variable name.");
+            }
+            match (lookup(name)?, default) {
+                (Some(value), _) => expanded.push_str(&value),
+                (None, Some(default)) => expanded.push_str(&expand_with(default, lookup)?),
+                (None, None) => bail!(
+                    "The environment variable {name} referenced by {{{placeholder}}} is not set \
+                    and the placeholder has no default."
+                ),
+            }
+        } else {
+            expanded.push_str(&rest[..=end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, char) in text.char_indices() {
+        match char {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    fn expand(text: &str) -> Result<String> {
+        super::expand_with(text, &|name| {
+            Ok(match name {
+                "MIRROR" => Some("mirror.example.org".to_string()),
+                "EMPTY" => Some(String::new()),
+                _ => None,
+            })
+        })
+    }
+
+    #[test]
+    fn expand_env() {
+        assert_eq!(
+            "https://mirror.example.org/file",
+            expand("https://{scie.env.MIRROR}/file").unwrap()
+        );
+        assert_eq!(
+            "https://mirror.example.org/file",
+            expand("https://{scie.env.MIRROR=example.org}/file").unwrap()
+        );
+        assert_eq!(
+            "https:///file",
+            expand("https://{scie.env.EMPTY}/file").unwrap()
+        );
+    }
+
+    #[test]
+    fn expand_default() {
+        assert_eq!(
+            "https://example.org/file",
+            expand("https://{scie.env.UNSET=example.org}/file").unwrap()
+        );
+        assert_eq!("Bearer ", expand("Bearer {scie.env.UNSET=}").unwrap());
+        assert_eq!(
+            "https://mirror.example.org/file",
+            expand("https://{scie.env.UNSET={scie.env.MIRROR}}/file").unwrap()
+        );
+    }
+
+    #[test]
+    fn expand_passthrough() {
+        assert_eq!("{scie.lift}", expand("{scie.lift}").unwrap());
+        assert_eq!("{literal}", expand("{{literal}").unwrap());
+        assert_eq!("no placeholders", expand("no placeholders").unwrap());
+    }
+
+    #[test]
+    fn expand_errors() {
+        let err = expand("Bearer {scie.env.TOKEN}").unwrap_err();
+        assert_eq!(
+            "The environment variable TOKEN referenced by {scie.env.TOKEN} is not set and the \
+            placeholder has no default.",
+            err.to_string()
+        );
+        assert!(expand("https://{scie.env.MIRROR/file").is_err());
+        assert!(expand("{scie.env.}").is_err());
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2022 Science project contributors.
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use serde_json::json;
+
+use crate::fetch::Response;
+use crate::redact::redact_url;
+
+/// The variables a `--write-out` format can refer to, as `%{name}`.
+const VARIABLES: &[&str] = &[
+    "content_type",
+    "http_code",
+    "num_redirects",
+    "remote_ip",
+    "remote_port",
+    "response_code",
+    "size_download",
+    "speed_download",
+    "time_appconnect",
+    "time_connect",
+    "time_namelookup",
+    "time_pretransfer",
+    "time_starttransfer",
+    "time_total",
+    "url",
+    "url_effective",
+];
+
+enum Segment {
+    Text(String),
+    Variable(&'static str),
+    Json,
+    Stdout,
+    Stderr,
+}
+
+enum Value {
+    Text(Option<String>),
+    Count(u64),
+    Port(Option<u16>),
+    Seconds(Duration),
+}
+
+impl Value {
+    fn to_text(&self) -> String {
+        match self {
+            Value::Text(text) => text.clone().unwrap_or_default(),
+            Value::Count(count) => count.to_string(),
+            Value::Port(port) => port.map(|port| port.to_string()).unwrap_or_default(),
+            // N.B.: Like curl, times are reported in seconds with microsecond precision.
+            Value::Seconds(duration) => format!("{:.6}", duration.as_secs_f64()),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Text(text) => json!(text),
+            Value::Count(count) => json!(count),
+            Value::Port(port) => json!(port),
+            Value::Seconds(duration) => json!(duration.as_micros() as f64 / 1_000_000.0),
+        }
+    }
+}
+
+fn value(name: &str, url: &str, response: &Response) -> Value {
+    let report = &response.report;
+    match name {
+        "content_type" => Value::Text(report.content_type.clone()),
+        "http_code" | "response_code" => Value::Count(report.response_code.into()),
+        "num_redirects" => Value::Count(report.redirect_count.into()),
+        "remote_ip" => Value::Text(report.remote_ip.clone()),
+        "remote_port" => Value::Port(report.remote_port),
+        "size_download" => Value::Count(report.download_size),
+        "speed_download" => Value::Count(report.download_speed()),
+        "time_appconnect" => Value::Seconds(report.appconnect_time),
+        "time_connect" => Value::Seconds(report.connect_time),
+        "time_namelookup" => Value::Seconds(report.namelookup_time),
+        "time_pretransfer" => Value::Seconds(report.pretransfer_time),
+        "time_starttransfer" => Value::Seconds(report.starttransfer_time),
+        "time_total" => Value::Seconds(report.total_time),
+        "url" => Value::Text(Some(redact_url(url))),
+        "url_effective" => Value::Text(Some(redact_url(&response.effective_url))),
+        _ => unreachable!("Variable names are validated when the format is parsed."),
+    }
+}
+
+/// A curl-style `--write-out` format describing a completed fetch.
+///
+/// The format is literal text with `%{variable}` substitutions, `%{json}` for a JSON object of
+/// all variables and `%{stdout}` and `%{stderr}` to switch where the following output goes. A
+/// `%%` is a literal `%` and `\n`, `\r`, `\t` and `\\` are the usual escapes.
+pub(crate) struct WriteOut {
+    segments: Vec<Segment>,
+}
+
+impl WriteOut {
+    pub(crate) fn parse(format: &str) -> Result<Self> {
+        let mut segments = vec![];
+        let mut text = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(char) = chars.next() {
+            match (char, chars.peek()) {
+                ('%', Some('%')) => {
+                    chars.next();
+                    text.push('%');
+                }
+                ('%', Some('{')) => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(char) => name.push(char),
+                            None => bail!("Unterminated variable %{{{name} in {format:?}."),
+                        }
+                    }
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(match name.as_str() {
+                        "json" => Segment::Json,
+                        "stdout" => Segment::Stdout,
+                        "stderr" => Segment::Stderr,
+                        name => Segment::Variable(
+                            VARIABLES
+                                .iter()
+                                .find(|variable| **variable == name)
+                                .with_context(|| {
+                                    format!(
+                                        "Unknown variable %{{{name}}}; expected one of json, \
+                                        stdout, stderr, {variables}.",
+                                        variables = VARIABLES.join(", ")
+                                    )
+                                })?,
+                        ),
+                    });
+                }
+                ('\\', Some(escaped @ ('n' | 'r' | 't' | '\\'))) => {
+                    text.push(match escaped {
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        _ => '\\',
+                    });
+                    chars.next();
+                }
+                (char, _) => text.push(char),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self { segments })
+    }
+
+    /// Writes the report of the fetch of `url` to stdout, or stderr once switched to it.
+    pub(crate) fn write(&self, url: &str, response: &Response) -> Result<()> {
+        self.write_to(
+            url,
+            response,
+            &mut std::io::stdout().lock(),
+            &mut std::io::stderr().lock(),
+        )
+        .context("Failed to write out the fetch report")
+    }
+
+    fn write_to(
+        &self,
+        url: &str,
+        response: &Response,
+        stdout: &mut dyn Write,
+        stderr: &mut dyn Write,
+    ) -> std::io::Result<()> {
+        let mut to_stderr = false;
+        for segment in &self.segments {
+            let out: &mut dyn Write = if to_stderr {
+                &mut *stderr
+            } else {
+                &mut *stdout
+            };
+            match segment {
+                Segment::Text(text) => out.write_all(text.as_bytes())?,
+                Segment::Variable(name) => {
+                    out.write_all(value(name, url, response).to_text().as_bytes())?
+                }
+                Segment::Json => {
+                    let json = serde_json::Value::Object(
+                        VARIABLES
+                            .iter()
+                            .map(|name| (name.to_string(), value(name, url, response).to_json()))
+                            .collect(),
+                    );
+                    out.write_all(json.to_string().as_bytes())?
+                }
+                Segment::Stdout => to_stderr = false,
+                Segment::Stderr => to_stderr = true,
+            }
+        }
+        stdout.flush()?;
+        stderr.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::WriteOut;
+    use crate::fetch::{Report, Response};
+
+    fn render(format: &str, response: &Response) -> (String, String) {
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        WriteOut::parse(format)
+            .unwrap()
+            .write_to(
+                "https://example.org/file",
+                response,
+                &mut stdout,
+                &mut stderr,
+            )
+            .unwrap();
+        (
+            String::from_utf8(stdout).unwrap(),
+            String::from_utf8(stderr).unwrap(),
+        )
+    }
+
+    fn response() -> Response {
+        Response {
+            effective_url: "https://mirror.example.org/file".to_string(),
+            report: Report {
+                response_code: 200,
+                redirect_count: 1,
+                remote_ip: Some("192.0.2.1".to_string()),
+                remote_port: Some(443),
+                download_size: 2048,
+                connect_time: Duration::from_micros(1500),
+                total_time: Duration::from_millis(500),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn write_out() {
+        assert_eq!(
+            (
+                "200 https://example.org/file -> https://mirror.example.org/file (1)\t\
+                %{time_connect}=0.001500 4096B/s \n"
+                    .to_string(),
+                String::new()
+            ),
+            render(
+                "%{http_code} %{url} -> %{url_effective} (%{num_redirects})\\t\
+                %%{time_connect}=%{time_connect} %{speed_download}B/s %{content_type}\\n",
+                &response()
+            )
+        );
+        assert_eq!(
+            ("50% done\n".to_string(), "0 bytes".to_string()),
+            render(
+                "50% done%{stderr}%{size_download} bytes%{stdout}\\n",
+                &Response::default()
+            )
+        );
+    }
+
+    #[test]
+    fn write_out_json() {
+        let (stdout, _) = render("%{json}", &response());
+        let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+        assert_eq!(200, json["http_code"]);
+        assert_eq!("https://mirror.example.org/file", json["url_effective"]);
+        assert_eq!(443, json["remote_port"]);
+        assert_eq!(0.0015, json["time_connect"]);
+        assert_eq!(0.5, json["time_total"]);
+        assert!(json["content_type"].is_null());
+    }
+
+    #[test]
+    fn write_out_invalid() {
+        assert!(WriteOut::parse("%{http_cod}").is_err());
+        assert!(WriteOut::parse("%{http_code").is_err());
+    }
+}
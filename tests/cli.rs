@@ -1,7 +1,10 @@
 // Copyright 2022 Science project contributors.
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use sha2::{Digest, Sha256};
 
@@ -52,3 +55,381 @@ fn fetch_remote_name() {
     assert!(local_file.is_file());
     assert_fetched_buffer(std::fs::read(local_file).unwrap().as_slice());
 }
+
+#[test]
+fn timeout_exit_code() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{addr}/file", addr = listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let _connection = listener.accept().unwrap();
+        std::thread::sleep(Duration::from_secs(10));
+    });
+    assert_eq!(
+        Some(28),
+        Command::new(PTEX)
+            .args(["--max-time", "0.5", &url])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .code()
+    );
+}
+
+fn exit_code(args: &[&str]) -> Option<i32> {
+    Command::new(PTEX)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap()
+        .code()
+}
+
+#[test]
+fn exit_codes() {
+    assert_eq!(Some(2), exit_code(&["--retry", "many", URL]));
+    assert_eq!(Some(2), exit_code(&["--limit-rate", "nank", URL]));
+    assert_eq!(Some(2), exit_code(&["--limit-rate", "1e400", URL]));
+    assert_eq!(Some(2), exit_code(&["--limit-rate", "16777216T", URL]));
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let manifest = tempdir.path().join("lift.json");
+    std::fs::write(&manifest, r#"{"ptex": {"other": "file:///dev/null"}}"#).unwrap();
+    let manifest = manifest.to_str().unwrap();
+    assert_eq!(Some(101), exit_code(&[manifest, "file"]));
+    assert_eq!(
+        Some(2),
+        exit_code(&[
+            "cache",
+            "prune",
+            "--cache-dir",
+            tempdir.path().join("cache").to_str().unwrap(),
+            "--max-age",
+            "1000000000000000d"
+        ])
+    );
+    assert_eq!(
+        Some(100),
+        exit_code(&[
+            tempdir.path().join("missing.json").to_str().unwrap(),
+            "file"
+        ])
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{addr}/file", addr = listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request);
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+    });
+    assert_eq!(Some(22), exit_code(&[&url]));
+}
+
+#[test]
+fn remote_name_atomic() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{addr}/file", addr = listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request);
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\npartial")
+            .unwrap();
+    });
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let local_file = tempdir.path().join("file");
+    std::fs::write(&local_file, b"original").unwrap();
+    assert!(
+        !Command::new(PTEX)
+            .args(["-s", "-O", &url])
+            .current_dir(&tempdir)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap()
+            .success()
+    );
+    assert_eq!(b"original", std::fs::read(&local_file).unwrap().as_slice());
+    assert_eq!(1, std::fs::read_dir(tempdir.path()).unwrap().count());
+}
+
+#[test]
+fn output_paths() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let source = tempdir.path().join("source.txt");
+    std::fs::write(&source, b"content").unwrap();
+    let url = format!("file://{path}", path = source.display());
+    let out = tempdir.path().join("out");
+
+    assert_eq!(
+        Some(23),
+        exit_code(&["-s", "-o", out.join("a/file").to_str().unwrap(), &url])
+    );
+    assert_eq!(
+        Some(0),
+        exit_code(&[
+            "-s",
+            "--create-dirs",
+            "-o",
+            out.join("a/file").to_str().unwrap(),
+            &url
+        ])
+    );
+    assert_eq!(
+        b"content",
+        std::fs::read(out.join("a/file")).unwrap().as_slice()
+    );
+
+    assert_eq!(
+        Some(0),
+        exit_code(&[
+            "-s",
+            "--create-dirs",
+            "--output-dir",
+            out.join("b").to_str().unwrap(),
+            "-O",
+            &url
+        ])
+    );
+    assert_eq!(
+        b"content",
+        std::fs::read(out.join("b/source.txt")).unwrap().as_slice()
+    );
+
+    assert_eq!(
+        Some(0),
+        exit_code(&[
+            "-s",
+            "--output-dir",
+            out.join("b").to_str().unwrap(),
+            "-o",
+            "renamed.txt",
+            &url
+        ])
+    );
+    assert_eq!(
+        b"content",
+        std::fs::read(out.join("b/renamed.txt")).unwrap().as_slice()
+    );
+
+    assert_eq!(Some(2), exit_code(&["-O", "-o", "file", &url]));
+}
+
+#[test]
+fn multiple_urls() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let url = |name: &str| {
+        let source = tempdir.path().join(name);
+        std::fs::write(&source, name).unwrap();
+        format!("file://{path}", path = source.display())
+    };
+    let (a, b) = (url("a.txt"), url("b.txt"));
+    let missing = format!(
+        "file://{path}",
+        path = tempdir.path().join("missing.txt").display()
+    );
+    let out = tempdir.path().join("out");
+    let out = out.to_str().unwrap();
+
+    assert_eq!(
+        Some(0),
+        exit_code(&["-s", "--create-dirs", "--output-dir", out, "-O", &a, &b])
+    );
+    assert_eq!(
+        b"a.txt",
+        std::fs::read(tempdir.path().join("out/a.txt"))
+            .unwrap()
+            .as_slice()
+    );
+    assert_eq!(
+        b"b.txt",
+        std::fs::read(tempdir.path().join("out/b.txt"))
+            .unwrap()
+            .as_slice()
+    );
+
+    let url_file = tempdir.path().join("urls.txt");
+    std::fs::write(
+        &url_file,
+        format!("# Fetched one at a time.\n{missing}\n\n{a} renamed.txt\n"),
+    )
+    .unwrap();
+    assert_eq!(
+        Some(1),
+        exit_code(&[
+            "-s",
+            "--output-dir",
+            out,
+            "--parallel-max",
+            "1",
+            "--url-file",
+            url_file.to_str().unwrap(),
+        ])
+    );
+    assert_eq!(
+        b"a.txt",
+        std::fs::read(tempdir.path().join("out/renamed.txt"))
+            .unwrap()
+            .as_slice()
+    );
+    assert!(!tempdir.path().join("out/missing.txt").exists());
+
+    assert_eq!(Some(2), exit_code(&["-o", "file", &a, &b]));
+    assert_eq!(Some(2), exit_code(&["-C", "-", "-O", &a, &b]));
+}
+
+#[test]
+fn verbose_redacts_credentials() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://user:s3cr3t@{addr}/file?token=s3cr3t",
+        addr = listener.local_addr().unwrap()
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nSet-Cookie: id=s3cr3t\r\nContent-Length: 7\r\n\r\ncontent",
+                )
+                .unwrap();
+        }
+    });
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let trace = tempdir.path().join("trace.log");
+    let output = Command::new(PTEX)
+        .args([
+            "-s",
+            "-v",
+            "--trace",
+            trace.to_str().unwrap(),
+            "-H",
+            "Authorization: Bearer s3cr3t",
+            "-o",
+            tempdir.path().join("file").to_str().unwrap(),
+            &url,
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("> Authorization: Bearer REDACTED"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("> GET /file?token=REDACTED HTTP/1.1"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("< Set-Cookie: REDACTED"), "{stderr}");
+    assert!(!stderr.contains("s3cr3t"), "{stderr}");
+    let trace = std::fs::read_to_string(trace).unwrap();
+    assert!(trace.contains("< 7 bytes of data"), "{trace}");
+    assert!(!trace.contains("s3cr3t"), "{trace}");
+}
+
+#[test]
+fn progress_lines_redact_credentials() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!(
+        "http://user:s3cr3t@{addr}/file?token=s3cr3t",
+        addr = listener.local_addr().unwrap()
+    );
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request);
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\ncontent")
+            .unwrap();
+    });
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let output = Command::new(PTEX)
+        .args([
+            "--progress-interval",
+            "0",
+            "-o",
+            tempdir.path().join("file").to_str().unwrap(),
+            &url,
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Downloaded http://"), "{stderr}");
+    assert!(stderr.contains("/file?token=REDACTED"), "{stderr}");
+    assert!(!stderr.contains("s3cr3t"), "{stderr}");
+}
+
+#[test]
+fn events_and_messages_redact_credentials() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let responses: [&[u8]; 4] = [
+            b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 0\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n",
+            b"HTTP/1.1 302 Found\r\nLocation: /moved?token=s3cr3t\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\ncontent",
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ];
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response).unwrap();
+        }
+    });
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let fetch = |path: &str| {
+        Command::new(PTEX)
+            .args([
+                "--progress",
+                "json",
+                "--retry",
+                "1",
+                "-w",
+                "%{url} %{url_effective}\n",
+                "-o",
+                tempdir.path().join("file").to_str().unwrap(),
+                &format!("http://user:s3cr3t@{addr}/{path}?token=s3cr3t"),
+            ])
+            .output()
+            .unwrap()
+    };
+
+    let output = fetch("file");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(r#""event":"retry""#), "{stderr}");
+    assert!(stderr.contains(r#""event":"redirect""#), "{stderr}");
+    assert!(stderr.contains("/moved?token=REDACTED"), "{stderr}");
+    assert!(!stderr.contains("s3cr3t"), "{stderr}");
+    assert!(stdout.contains("/moved?token=REDACTED"), "{stdout}");
+    assert!(!stdout.contains("s3cr3t"), "{stdout}");
+
+    let output = fetch("missing");
+    assert_eq!(Some(22), output.status.code());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(r#""event":"error""#), "{stderr}");
+    assert!(stderr.contains("Failed to fetch http://"), "{stderr}");
+    assert!(!stderr.contains("s3cr3t"), "{stderr}");
+}